@@ -0,0 +1,15 @@
+-- Index of @WaifuP1c posts, so a pull doesn't have to probe random post ids
+CREATE TABLE IF NOT EXISTS channel_posts (
+    post_id INTEGER PRIMARY KEY,
+    -- NULL if no character could be extracted from the caption
+    character TEXT,
+    -- Telegram photo id, NULL if the post has no photo
+    photo_id INTEGER,
+    posted_at DATETIME NOT NULL,
+    -- whether the post can be served as a prize
+    valid BOOLEAN NOT NULL DEFAULT 1,
+
+    indexed_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX IF NOT EXISTS idx_channel_posts_valid ON channel_posts (valid, post_id);
@@ -0,0 +1,5 @@
+ALTER TABLE channel_posts ADD COLUMN series TEXT;
+ALTER TABLE channel_posts ADD COLUMN artist TEXT;
+
+CREATE INDEX IF NOT EXISTS idx_channel_posts_unmatched ON channel_posts (post_id)
+WHERE photo_id IS NOT NULL AND character IS NULL;
@@ -0,0 +1,21 @@
+-- A post may feature several characters. channel_posts.character keeps the first one.
+CREATE TABLE IF NOT EXISTS channel_post_characters (
+    post_id INTEGER NOT NULL REFERENCES channel_posts (post_id),
+    character TEXT NOT NULL,
+    PRIMARY KEY (post_id, character)
+);
+
+CREATE INDEX IF NOT EXISTS idx_channel_post_characters_character ON channel_post_characters (character);
+
+INSERT OR IGNORE INTO channel_post_characters (post_id, character)
+SELECT post_id, character FROM channel_posts WHERE character IS NOT NULL;
+
+-- Every character a user has pulled, a post with two characters credits both
+CREATE TABLE IF NOT EXISTS user_characters (
+    user_id INTEGER NOT NULL,
+    character TEXT NOT NULL,
+    pulls INTEGER NOT NULL DEFAULT 1,
+    first_pulled_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    last_pulled_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY (user_id, character)
+);
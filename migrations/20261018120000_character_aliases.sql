@@ -0,0 +1,18 @@
+CREATE TABLE IF NOT EXISTS characters (
+    character_id INTEGER PRIMARY KEY,
+    -- the raw name the character was first seen as
+    canonical_name TEXT NOT NULL,
+    name_zh TEXT,
+    name_ja TEXT,
+    name_en TEXT,
+
+    created_at DATETIME DEFAULT CURRENT_TIMESTAMP
+);
+
+-- Raw names as they appear in captions and collections, normalized (trimmed, no '#', lowercase)
+CREATE TABLE IF NOT EXISTS character_aliases (
+    alias TEXT PRIMARY KEY,
+    character_id INTEGER NOT NULL REFERENCES characters (character_id)
+);
+
+CREATE INDEX IF NOT EXISTS idx_character_aliases_character_id ON character_aliases (character_id);
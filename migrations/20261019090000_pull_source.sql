@@ -0,0 +1,2 @@
+-- Which source in the fallback chain served the user's last pull, see `PullSource::as_str`
+ALTER TABLE users ADD COLUMN pull_source TEXT;
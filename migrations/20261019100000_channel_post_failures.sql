@@ -0,0 +1,7 @@
+-- Channel posts that failed to index (embed scrape errors, open circuits), retried by backfill
+CREATE TABLE IF NOT EXISTS channel_post_failures (
+    post_id INTEGER PRIMARY KEY,
+    attempts INTEGER NOT NULL DEFAULT 1,
+    last_error TEXT NOT NULL,
+    last_attempt_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
@@ -0,0 +1,19 @@
+-- Collections keyed by canonical character instead of the raw names in user_characters.
+-- Rows whose name matches an alias move over here, `characters::init` moves the rest.
+CREATE TABLE IF NOT EXISTS user_collection (
+    user_id INTEGER NOT NULL,
+    character_id INTEGER NOT NULL REFERENCES characters (character_id),
+    pulls INTEGER NOT NULL DEFAULT 1,
+    first_pulled_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    last_pulled_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY (user_id, character_id)
+);
+
+INSERT INTO user_collection (user_id, character_id, pulls, first_pulled_at, last_pulled_at)
+SELECT u.user_id, a.character_id, SUM(u.pulls), MIN(u.first_pulled_at), MAX(u.last_pulled_at)
+FROM user_characters u
+JOIN character_aliases a ON a.alias = lower(ltrim(trim(u.character), '#'))
+GROUP BY u.user_id, a.character_id;
+
+DELETE FROM user_characters
+WHERE lower(ltrim(trim(character), '#')) IN (SELECT alias FROM character_aliases);
@@ -0,0 +1,2 @@
+-- Kana reading of a kanji name, so romaji searches find it (はつねミク for 初音ミク)
+ALTER TABLE characters ADD COLUMN reading TEXT;
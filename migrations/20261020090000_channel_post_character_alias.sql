@@ -0,0 +1,5 @@
+-- The normalized alias of each raw character name, see `characters::normalize`.
+-- SQLite's lower() only folds ASCII, so existing rows are filled in by the bot at startup.
+ALTER TABLE channel_post_characters ADD COLUMN alias TEXT;
+
+CREATE INDEX IF NOT EXISTS idx_channel_post_characters_alias ON channel_post_characters (alias);
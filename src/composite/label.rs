@@ -0,0 +1,159 @@
+// Numbered badges drawn onto composite tiles, with the embedded DejaVu Sans Bold.
+use super::{blend, style};
+use crate::config::LABEL_CORNER;
+use ab_glyph::{Font, FontRef, PxScale, ScaleFont, point};
+use anyhow::anyhow;
+use image::{Rgba, RgbaImage};
+use std::str::FromStr;
+use std::sync::LazyLock;
+
+static FONT: LazyLock<FontRef<'static>> = LazyLock::new(|| {
+    FontRef::try_from_slice(include_bytes!("../../assets/DejaVuSans-Bold.ttf"))
+        .expect("Embedded font is invalid")
+});
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Corner {
+    TopLeft,
+    TopRight,
+    BottomLeft,
+    BottomRight,
+}
+
+impl FromStr for Corner {
+    type Err = anyhow::Error;
+
+    /// `top-left`, `top-right`, `bottom-left` or `bottom-right`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim() {
+            "top-left" => Ok(Self::TopLeft),
+            "top-right" => Ok(Self::TopRight),
+            "bottom-left" => Ok(Self::BottomLeft),
+            "bottom-right" => Ok(Self::BottomRight),
+            _ => Err(anyhow!("Unknown corner '{s}'")),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct LabelConfig {
+    pub enabled: bool,
+    pub corner: Corner,
+    /// Font size of the number in px, the badge is a bit larger
+    pub size: f32,
+    /// Distance between the badge and the tile edges in px
+    pub margin: f32,
+    pub text_color: Rgba<u8>,
+    pub badge_color: Rgba<u8>,
+    /// Stroke around the text, so it stays readable without a badge
+    pub outline_width: f32,
+    pub outline_color: Rgba<u8>,
+}
+
+impl Default for LabelConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            corner: *LABEL_CORNER,
+            size: 36.,
+            margin: 12.,
+            text_color: Rgba([255, 255, 255, 255]),
+            badge_color: Rgba([0, 0, 0, 160]),
+            outline_width: 2.,
+            outline_color: Rgba([0, 0, 0, 255]),
+        }
+    }
+}
+
+/// `tile` is (left, top, width, height) on the canvas. Names aren't drawn, the embedded font
+/// has no CJK glyphs and most names are CJK. The caption lists them instead.
+pub fn draw(
+    canvas: &mut RgbaImage,
+    config: &LabelConfig,
+    tile: (f32, f32, f32, f32),
+    number: &str,
+) {
+    if !config.enabled {
+        return;
+    }
+    let (left, top, width, height) = tile;
+    let scale = PxScale::from(config.size);
+
+    // A circle for "1", a pill for "10"
+    let badge_h = config.size * 1.4;
+    let badge_w = (text_width(number, scale) + config.size * 0.8).max(badge_h);
+    let x = match config.corner {
+        Corner::TopLeft | Corner::BottomLeft => left + config.margin,
+        Corner::TopRight | Corner::BottomRight => left + width - config.margin - badge_w,
+    };
+    let y = match config.corner {
+        Corner::TopLeft | Corner::TopRight => top + config.margin,
+        Corner::BottomLeft | Corner::BottomRight => top + height - config.margin - badge_h,
+    };
+    let badge = (x, y, badge_w, badge_h);
+    style::fill_rounded_rect(canvas, badge, badge_h / 2., 1., config.badge_color);
+    let text_x = x + (badge_w - text_width(number, scale)) / 2.;
+    let text_y = y + (badge_h - config.size) / 2.;
+    draw_outlined_text(canvas, config, number, text_x, text_y, scale);
+}
+
+fn draw_outlined_text(
+    canvas: &mut RgbaImage,
+    config: &LabelConfig,
+    text: &str,
+    x: f32,
+    y: f32,
+    scale: PxScale,
+) {
+    let o = config.outline_width;
+    if o > 0. {
+        for (dx, dy) in [
+            (-o, -o),
+            (0., -o),
+            (o, -o),
+            (-o, 0.),
+            (o, 0.),
+            (-o, o),
+            (0., o),
+            (o, o),
+        ] {
+            draw_text(canvas, text, x + dx, y + dy, scale, config.outline_color);
+        }
+    }
+    draw_text(canvas, text, x, y, scale, config.text_color);
+}
+
+/// `y` is the top of the line. Glyphs missing from the font are skipped.
+fn draw_text(canvas: &mut RgbaImage, text: &str, x: f32, y: f32, scale: PxScale, color: Rgba<u8>) {
+    let font = FONT.as_scaled(scale);
+    let mut caret = x;
+    for ch in text.chars() {
+        let id = font.glyph_id(ch);
+        if id.0 == 0 {
+            continue;
+        }
+        let glyph = id.with_scale_and_position(scale, point(caret, y + font.ascent()));
+        caret += font.h_advance(id);
+        if let Some(outlined) = FONT.outline_glyph(glyph) {
+            let bounds = outlined.px_bounds();
+            outlined.draw(|gx, gy, coverage| {
+                blend(
+                    canvas,
+                    bounds.min.x as i32 + gx as i32,
+                    bounds.min.y as i32 + gy as i32,
+                    color,
+                    coverage,
+                );
+            });
+        }
+    }
+}
+
+fn text_width(text: &str, scale: PxScale) -> f32 {
+    let font = FONT.as_scaled(scale);
+    text.chars()
+        .map(|ch| font.glyph_id(ch))
+        .filter(|id| id.0 != 0)
+        .map(|id| font.h_advance(id))
+        .sum()
+}
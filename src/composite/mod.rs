@@ -0,0 +1,544 @@
+// Ten-pull composite: tiles placed by `layout::compute`, each with a numbered badge
+// so users can tell which button picks which picture, styled by a `Theme`.
+pub mod label;
+pub mod style;
+use crate::config::{COMPOSITE_FORMAT, LAYOUT_ALGORITHM};
+use crate::layout::{self, LayoutConfig};
+use anyhow::{Result, anyhow};
+use bytes::Bytes;
+use image::{DynamicImage, ImageFormat, ImageReader, Rgba, RgbaImage, imageops::FilterType};
+use label::LabelConfig;
+use std::io::Cursor;
+use std::str::FromStr;
+use std::sync::LazyLock;
+use std::time::Instant;
+pub use style::{Frame, Theme};
+use tokio::sync::Semaphore;
+
+/// Cached tiles are at most this large, enough for any row height we use
+const CACHED_TILE_MAX_SIDE: u32 = 1024;
+
+/// Telegram rejects photos beyond these, anything else goes as a document
+const MAX_PHOTO_BYTES: usize = 10 * 1024 * 1024;
+const MAX_PHOTO_SIDES: u32 = 10000;
+const MAX_PHOTO_ASPECT_RATIO: f64 = 20.;
+/// Bots can't upload files larger than this at all
+const MAX_DOCUMENT_BYTES: usize = 50 * 1024 * 1024;
+/// Lossy encodings step down by this much before we start downscaling
+const QUALITY_STEP: u8 = 10;
+const MIN_QUALITY: u8 = 30;
+/// Each downscale keeps this much of the sides
+const DOWNSCALE_FACTOR: f64 = 0.8;
+
+/// Decoding, resizing and encoding block for hundreds of milliseconds, so they run on
+/// tokio's blocking threads, at most one job per core at a time
+static IMAGE_JOBS: LazyLock<Semaphore> =
+    LazyLock::new(|| Semaphore::new(std::thread::available_parallelism().map_or(2, |n| n.get())));
+
+#[derive(Debug, Clone)]
+pub struct CompositeConfig {
+    pub layout: LayoutConfig,
+    pub labels: LabelConfig,
+    pub format: OutputFormat,
+}
+
+impl Default for CompositeConfig {
+    fn default() -> Self {
+        Self {
+            layout: LayoutConfig {
+                algorithm: *LAYOUT_ALGORITHM,
+                container_width: 1300.,
+                target_row_height: vec![440., 500.],
+                target_row_height_tolerance: 0.1,
+                edge_case_min_row_height_factor: 0.8,
+                edge_case_max_row_height_factor: 1.5,
+                // Chat previews crop anything much taller than it is wide
+                min_container_aspect_ratio: Some(0.5),
+                ..Default::default()
+            },
+            labels: LabelConfig::default(),
+            format: *COMPOSITE_FORMAT,
+        }
+    }
+}
+
+/// How the composite is encoded. Telegram converts photos to JPEG anyway, the others
+/// mostly matter when we end up sending a document.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OutputFormat {
+    /// quality: 1..=100
+    Jpeg {
+        quality: u8,
+    },
+    /// quality: 0..=100
+    WebpLossy {
+        quality: u8,
+    },
+    WebpLossless,
+    Png,
+}
+
+impl OutputFormat {
+    fn extension(&self) -> &'static str {
+        match self {
+            Self::Jpeg { .. } => "jpg",
+            Self::WebpLossy { .. } | Self::WebpLossless => "webp",
+            Self::Png => "png",
+        }
+    }
+
+    /// The next step down, None if there's no quality to give up
+    fn lower_quality(&self) -> Option<Self> {
+        let lower = |quality: u8| {
+            (quality > MIN_QUALITY).then(|| quality.saturating_sub(QUALITY_STEP).max(MIN_QUALITY))
+        };
+        match *self {
+            Self::Jpeg { quality } => lower(quality).map(|quality| Self::Jpeg { quality }),
+            Self::WebpLossy { quality } => {
+                lower(quality).map(|quality| Self::WebpLossy { quality })
+            }
+            Self::WebpLossless | Self::Png => None,
+        }
+    }
+
+    fn encode(&self, image: &DynamicImage) -> Result<Vec<u8>> {
+        let mut buffer = vec![];
+        match *self {
+            Self::Jpeg { quality } => {
+                let encoder =
+                    image::codecs::jpeg::JpegEncoder::new_with_quality(&mut buffer, quality);
+                DynamicImage::ImageRgb8(image.to_rgb8()).write_with_encoder(encoder)?;
+            }
+            Self::WebpLossy { quality } => {
+                let image = DynamicImage::ImageRgba8(image.to_rgba8());
+                let encoder = webp::Encoder::from_image(&image).map_err(|e| anyhow!("{e}"))?;
+                buffer.extend_from_slice(&encoder.encode(quality as f32));
+            }
+            Self::WebpLossless => {
+                let image = DynamicImage::ImageRgba8(image.to_rgba8());
+                let encoder = webp::Encoder::from_image(&image).map_err(|e| anyhow!("{e}"))?;
+                buffer.extend_from_slice(&encoder.encode_lossless());
+            }
+            Self::Png => image.write_to(&mut Cursor::new(&mut buffer), ImageFormat::Png)?,
+        }
+        Ok(buffer)
+    }
+}
+
+impl FromStr for OutputFormat {
+    type Err = anyhow::Error;
+
+    /// `jpeg:60`, `webp:75`, `webp-lossless` or `png`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, quality) = match s.trim().split_once(':') {
+            Some((name, quality)) => (name, Some(quality.trim().parse::<u8>()?)),
+            None => (s.trim(), None),
+        };
+        match (name, quality) {
+            ("jpeg" | "jpg", Some(quality @ 1..=100)) => Ok(Self::Jpeg { quality }),
+            ("webp", Some(quality @ 0..=100)) => Ok(Self::WebpLossy { quality }),
+            ("webp-lossless", None) => Ok(Self::WebpLossless),
+            ("png", None) => Ok(Self::Png),
+            _ => Err(anyhow!("Unknown output format '{s}'")),
+        }
+    }
+}
+
+/// An encoded composite, ready to upload
+pub struct Composite {
+    pub content: Bytes,
+    pub file_name: String,
+    /// Doesn't fit Telegram's photo limits, send it as a file
+    pub as_document: bool,
+}
+
+/// A picture for the composite
+pub struct Tile {
+    pub image: DynamicImage,
+    pub frame: Frame,
+}
+
+async fn run_blocking<T: Send + 'static>(
+    job: impl FnOnce() -> Result<T> + Send + 'static,
+) -> Result<T> {
+    let _permit = IMAGE_JOBS.acquire().await?;
+    tokio::task::spawn_blocking(job).await?
+}
+
+pub async fn decode(bytes: Bytes) -> Result<DynamicImage> {
+    run_blocking(move || {
+        let started = Instant::now();
+        let image = ImageReader::new(Cursor::new(bytes))
+            .with_guessed_format()?
+            .decode()?;
+        tracing::debug!(
+            width = image.width(),
+            height = image.height(),
+            elapsed = ?started.elapsed(),
+            "Decoded"
+        );
+        Ok(image)
+    })
+    .await
+}
+
+pub async fn create_composite(
+    tiles: Vec<Tile>,
+    config: CompositeConfig,
+    theme: Theme,
+) -> Result<Composite> {
+    run_blocking(move || {
+        let canvas = render(tiles, &config, &theme)?;
+        encode_for_telegram(DynamicImage::ImageRgba8(canvas), config.format)
+    })
+    .await
+}
+
+/// Shrink to fit `CACHED_TILE_MAX_SIDE` and encode, for `image_cache`
+pub async fn encode_for_cache(image: DynamicImage) -> Result<Bytes> {
+    run_blocking(move || {
+        let image = if image.width().max(image.height()) > CACHED_TILE_MAX_SIDE {
+            image.resize(
+                CACHED_TILE_MAX_SIDE,
+                CACHED_TILE_MAX_SIDE,
+                FilterType::Lanczos3,
+            )
+        } else {
+            image
+        };
+        let mut buffer = vec![];
+        let encoder = image::codecs::jpeg::JpegEncoder::new_with_quality(&mut buffer, 90);
+        DynamicImage::ImageRgb8(image.into_rgb8()).write_with_encoder(encoder)?;
+        Ok(buffer.into())
+    })
+    .await
+}
+
+/// Stands in for a tile whose image we couldn't get
+pub fn placeholder((width, height): (u32, u32)) -> DynamicImage {
+    let (width, height) = (width.max(1), height.max(1));
+    DynamicImage::ImageRgba8(RgbaImage::from_pixel(
+        width,
+        height,
+        Rgba([128, 128, 136, 255]),
+    ))
+}
+
+/// Tile sizes for photos with these aspect ratios, to pick which rendition to download
+pub fn tile_sizes(ratios: &[f64], config: &CompositeConfig) -> Result<Vec<(u32, u32)>> {
+    Ok(layout::compute(ratios, &config.layout)?
+        .boxes
+        .iter()
+        .map(|p| (p.width.round() as u32, p.height.round() as u32))
+        .collect())
+}
+
+/// The smallest candidate that covers `target`, the largest one if none does.
+/// A pixel short still covers it, tile sizes are rounded.
+pub fn pick_size<T>(
+    candidates: impl IntoIterator<Item = (T, (u32, u32))>,
+    (target_w, target_h): (u32, u32),
+) -> Option<T> {
+    let area = |&(w, h): &(u32, u32)| w as u64 * h as u64;
+    let (large_enough, too_small): (Vec<_>, Vec<_>) = candidates
+        .into_iter()
+        .partition(|(_, (w, h))| w + 1 >= target_w && h + 1 >= target_h);
+    large_enough
+        .into_iter()
+        .min_by_key(|(_, size)| area(size))
+        .or_else(|| too_small.into_iter().max_by_key(|(_, size)| area(size)))
+        .map(|(candidate, _)| candidate)
+}
+
+fn render(tiles: Vec<Tile>, config: &CompositeConfig, theme: &Theme) -> Result<RgbaImage> {
+    let started = Instant::now();
+    let ratios = tiles
+        .iter()
+        .map(|tile| tile.image.width() as f64 / tile.image.height() as f64)
+        .collect::<Vec<_>>();
+
+    let layout_result = layout::compute(&ratios, &config.layout)?;
+    let placements = layout_result.boxes;
+
+    // 1. Determine the required canvas size
+    let max_w = config.layout.container_width;
+    let max_h = layout_result.container_height;
+
+    // 2. Paint the background, JPEG would turn a transparent canvas black
+    let mut canvas = RgbaImage::new(max_w.ceil() as u32, max_h.ceil() as u32);
+    style::fill_background(&mut canvas, theme.background);
+
+    // 3. Resize and overlay
+    for (i, (tile, p)) in tiles.into_iter().zip(placements).enumerate() {
+        let target_w = p.width.round() as u32;
+        let target_h = p.height.round() as u32;
+
+        let (left, top) = (p.left.round() as i64, p.top.round() as i64);
+        let rect = (left as f32, top as f32, target_w as f32, target_h as f32);
+
+        // Shadow and frame go under the tile
+        if let Some(shadow) = theme.shadow {
+            let shadow_rect = (
+                rect.0 + shadow.offset_x - theme.frame_width,
+                rect.1 + shadow.offset_y - theme.frame_width,
+                rect.2 + 2. * theme.frame_width,
+                rect.3 + 2. * theme.frame_width,
+            );
+            let radius = theme.corner_radius + theme.frame_width;
+            style::fill_rounded_rect(&mut canvas, shadow_rect, radius, shadow.blur, shadow.color);
+        }
+        if theme.frame_width > 0. {
+            let frame_rect = (
+                rect.0 - theme.frame_width,
+                rect.1 - theme.frame_width,
+                rect.2 + 2. * theme.frame_width,
+                rect.3 + 2. * theme.frame_width,
+            );
+            let radius = theme.corner_radius + theme.frame_width;
+            let color = theme.frame_colors.of(tile.frame);
+            style::fill_rounded_rect(&mut canvas, frame_rect, radius, 1., color);
+        }
+
+        // Resize the image to fit the placement, crop it if the layout decided the shape
+        let resized = if p.forced_aspect_ratio {
+            tile.image
+                .resize_to_fill(target_w, target_h, FilterType::Lanczos3)
+        } else {
+            tile.image
+                .resize_exact(target_w, target_h, FilterType::Lanczos3)
+        };
+        let mut resized = resized.into_rgba8();
+        style::round_corners(&mut resized, theme.corner_radius);
+
+        // Overlay onto the canvas
+        image::imageops::overlay(&mut canvas, &resized, left, top);
+
+        // 4. Badge with the button number, buttons are in pull order
+        label::draw(&mut canvas, &config.labels, rect, &(i + 1).to_string());
+    }
+
+    tracing::debug!(elapsed = ?started.elapsed(), "Composite drawn");
+    Ok(canvas)
+}
+
+/// Encode within Telegram's photo limits: shrink if the sides are too long, then give up
+/// quality, then resolution until it's small enough. Too narrow or hopeless goes as a document,
+/// which has a size limit of its own.
+fn encode_for_telegram(image: DynamicImage, format: OutputFormat) -> Result<Composite> {
+    let started = Instant::now();
+    let (width, height) = (image.width().max(1), image.height().max(1));
+    let aspect_ratio = (width as f64 / height as f64).max(height as f64 / width as f64);
+    let too_large = || anyhow!("Composite too large even for a document");
+
+    let (content, format, as_document) = if aspect_ratio > MAX_PHOTO_ASPECT_RATIO {
+        tracing::warn!(
+            width,
+            height,
+            "Composite too narrow for a photo, sending a document"
+        );
+        let (content, format) =
+            shrink_to_fit(&image, format, MAX_DOCUMENT_BYTES)?.ok_or_else(too_large)?;
+        (content, format, true)
+    } else {
+        let mut image = image;
+        if width + height > MAX_PHOTO_SIDES {
+            image = downscale(&image, MAX_PHOTO_SIDES as f64 / (width + height) as f64);
+        }
+        match shrink_to_fit(&image, format, MAX_PHOTO_BYTES)? {
+            Some((content, format)) => (content, format, false),
+            None => {
+                tracing::warn!("Composite won't fit a photo, sending a document");
+                let (content, format) =
+                    shrink_to_fit(&image, format, MAX_DOCUMENT_BYTES)?.ok_or_else(too_large)?;
+                (content, format, true)
+            }
+        }
+    };
+    tracing::debug!(
+        size = content.len(),
+        ?format,
+        as_document,
+        elapsed = ?started.elapsed(),
+        "Composite encoded"
+    );
+    Ok(Composite {
+        content: content.into(),
+        file_name: format!("composite.{}", format.extension()),
+        as_document,
+    })
+}
+
+/// Give up quality, then resolution until the encoding is at most `max_bytes`.
+/// None if it still doesn't fit once the shorter side is down to 100 px.
+fn shrink_to_fit(
+    image: &DynamicImage,
+    format: OutputFormat,
+    max_bytes: usize,
+) -> Result<Option<(Vec<u8>, OutputFormat)>> {
+    let mut format = format;
+    let mut smaller = None;
+    loop {
+        let current = smaller.as_ref().unwrap_or(image);
+        let content = format.encode(current)?;
+        if content.len() <= max_bytes {
+            return Ok(Some((content, format)));
+        }
+        if let Some(lower) = format.lower_quality() {
+            format = lower;
+        } else if current.width().min(current.height()) > 100 {
+            smaller = Some(downscale(current, DOWNSCALE_FACTOR));
+        } else {
+            return Ok(None);
+        }
+    }
+}
+
+fn downscale(image: &DynamicImage, factor: f64) -> DynamicImage {
+    let width = ((image.width() as f64 * factor).floor() as u32).max(1);
+    let height = ((image.height() as f64 * factor).floor() as u32).max(1);
+    image.resize_exact(width, height, FilterType::Lanczos3)
+}
+
+/// Alpha-blend `color` onto the pixel, `coverage` is the antialiasing coverage in 0..=1
+fn blend(canvas: &mut RgbaImage, x: i32, y: i32, color: Rgba<u8>, coverage: f32) {
+    if x < 0 || y < 0 || x >= canvas.width() as i32 || y >= canvas.height() as i32 {
+        return;
+    }
+    let alpha = coverage.clamp(0., 1.) * color[3] as f32 / 255.;
+    let pixel = canvas.get_pixel_mut(x as u32, y as u32);
+    for c in 0..3 {
+        pixel[c] = (pixel[c] as f32 * (1. - alpha) + color[c] as f32 * alpha).round() as u8;
+    }
+    pixel[3] = pixel[3].max((alpha * 255.).round() as u8);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Incompressible pixels, so encodings stay large
+    fn noise(width: u32, height: u32) -> DynamicImage {
+        let mut state = 0x2545_f491_4f6c_dd1d_u64;
+        DynamicImage::ImageRgb8(image::RgbImage::from_fn(width, height, |_, _| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            image::Rgb([state as u8, (state >> 8) as u8, (state >> 16) as u8])
+        }))
+    }
+
+    #[test]
+    fn parse_output_formats() {
+        let cases = [
+            ("jpeg:60", Some(OutputFormat::Jpeg { quality: 60 })),
+            ("jpg:100", Some(OutputFormat::Jpeg { quality: 100 })),
+            (" jpeg: 1 ", Some(OutputFormat::Jpeg { quality: 1 })),
+            ("webp:75", Some(OutputFormat::WebpLossy { quality: 75 })),
+            ("webp:0", Some(OutputFormat::WebpLossy { quality: 0 })),
+            ("webp-lossless", Some(OutputFormat::WebpLossless)),
+            ("png", Some(OutputFormat::Png)),
+            ("jpeg", None),
+            ("jpeg:0", None),
+            ("jpeg:101", None),
+            ("webp", None),
+            ("webp:x", None),
+            ("png:50", None),
+            ("gif", None),
+            ("", None),
+        ];
+        for (s, expected) in cases {
+            assert_eq!(s.parse::<OutputFormat>().ok(), expected, "{s:?}");
+        }
+    }
+
+    #[test]
+    fn quality_steps_down_to_the_minimum() {
+        let steps = std::iter::successors(Some(OutputFormat::Jpeg { quality: 75 }), |format| {
+            format.lower_quality()
+        })
+        .collect::<Vec<_>>();
+        let qualities = steps
+            .iter()
+            .map(|format| match format {
+                OutputFormat::Jpeg { quality } => *quality,
+                _ => unreachable!(),
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(qualities, [75, 65, 55, 45, 35, 30]);
+        assert_eq!(
+            OutputFormat::WebpLossy { quality: 20 }.lower_quality(),
+            None
+        );
+        assert_eq!(OutputFormat::WebpLossless.lower_quality(), None);
+        assert_eq!(OutputFormat::Png.lower_quality(), None);
+    }
+
+    #[test]
+    fn tile_sizes_are_rounded_placements() {
+        let config = CompositeConfig {
+            layout: LayoutConfig {
+                algorithm: layout::LayoutAlgorithm::Grid { columns: 2 },
+                container_width: 1001.,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        // (1001 - 2 * 10 - 10) / 2 = 485.5 wide cells
+        let sizes = tile_sizes(&[1., 1., 1.], &config).unwrap();
+        assert_eq!(sizes, [(486, 486); 3]);
+    }
+
+    #[test]
+    fn pick_size_table() {
+        let sizes = [("s", (90, 90)), ("m", (320, 240)), ("x", (800, 600))];
+        let cases = [
+            // Exact fit
+            ((320, 240), Some("m")),
+            ((800, 600), Some("x")),
+            // The smallest that covers
+            ((100, 100), Some("m")),
+            ((1, 1), Some("s")),
+            // A pixel short is close enough, two are not
+            ((321, 241), Some("m")),
+            ((322, 240), Some("x")),
+            // Nothing covers it, the largest it is
+            ((1000, 100), Some("x")),
+        ];
+        for (target, expected) in cases {
+            assert_eq!(pick_size(sizes, target), expected, "{target:?}");
+        }
+        assert_eq!(pick_size::<&str>([], (100, 100)), None);
+    }
+
+    #[test]
+    fn large_photo_is_shrunk_under_the_limit() {
+        // About 11.2 MB of raw pixels, PNG can't compress noise
+        let composite = encode_for_telegram(noise(2200, 1700), OutputFormat::Png).unwrap();
+        assert!(!composite.as_document);
+        assert!(composite.content.len() <= MAX_PHOTO_BYTES);
+        assert_eq!(composite.file_name, "composite.png");
+        let image = image::load_from_memory(&composite.content).unwrap();
+        assert_eq!((image.width(), image.height()), (1760, 1360));
+    }
+
+    #[test]
+    fn long_sides_are_downscaled() {
+        let image = DynamicImage::ImageRgb8(image::RgbImage::new(9600, 500));
+        let composite = encode_for_telegram(image, OutputFormat::Jpeg { quality: 60 }).unwrap();
+        assert!(!composite.as_document);
+        let image = image::load_from_memory(&composite.content).unwrap();
+        assert!(image.width() + image.height() <= MAX_PHOTO_SIDES);
+        assert_eq!(image.width(), 9504);
+    }
+
+    #[test]
+    fn narrow_composite_is_a_document() {
+        let image = DynamicImage::ImageRgb8(image::RgbImage::new(2100, 100));
+        let composite = encode_for_telegram(image, OutputFormat::Png).unwrap();
+        assert!(composite.as_document);
+        assert!(composite.content.len() <= MAX_DOCUMENT_BYTES);
+        // Not shrunk, only too large documents are
+        let image = image::load_from_memory(&composite.content).unwrap();
+        assert_eq!((image.width(), image.height()), (2100, 100));
+    }
+}
@@ -0,0 +1,195 @@
+// Composite themes: background, tile corners, shadows and frame colours.
+use super::blend;
+use chrono::{Datelike, NaiveDate};
+use image::{Rgba, RgbaImage};
+
+#[derive(Debug, Clone)]
+pub struct Theme {
+    pub background: Background,
+    /// Tile corner radius in px, 0 for square tiles
+    pub corner_radius: f32,
+    pub shadow: Option<Shadow>,
+    /// Frame around each tile in px, 0 for none
+    pub frame_width: f32,
+    pub frame_colors: FrameColors,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum Background {
+    Solid(Rgba<u8>),
+    /// Top to bottom
+    VerticalGradient(Rgba<u8>, Rgba<u8>),
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Shadow {
+    pub offset_x: f32,
+    pub offset_y: f32,
+    /// Width of the soft edge in px
+    pub blur: f32,
+    pub color: Rgba<u8>,
+}
+
+/// How special a tile is, picks the frame colour
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Frame {
+    Common,
+    /// A character the user has never pulled
+    New,
+    /// The user's special prize
+    Special,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct FrameColors {
+    pub common: Rgba<u8>,
+    pub new: Rgba<u8>,
+    pub special: Rgba<u8>,
+}
+
+impl FrameColors {
+    pub fn of(&self, frame: Frame) -> Rgba<u8> {
+        match frame {
+            Frame::Common => self.common,
+            Frame::New => self.new,
+            Frame::Special => self.special,
+        }
+    }
+}
+
+impl Default for Theme {
+    fn default() -> Self {
+        Self {
+            background: Background::VerticalGradient(
+                Rgba([38, 40, 52, 255]),
+                Rgba([18, 19, 26, 255]),
+            ),
+            corner_radius: 16.,
+            shadow: Some(Shadow {
+                offset_x: 0.,
+                offset_y: 4.,
+                blur: 10.,
+                color: Rgba([0, 0, 0, 150]),
+            }),
+            frame_width: 4.,
+            frame_colors: FrameColors {
+                common: Rgba([200, 200, 210, 255]),
+                new: Rgba([90, 170, 255, 255]),
+                special: Rgba([255, 196, 60, 255]),
+            },
+        }
+    }
+}
+
+impl Theme {
+    pub fn winter() -> Self {
+        Self {
+            background: Background::VerticalGradient(
+                Rgba([205, 225, 245, 255]),
+                Rgba([120, 160, 205, 255]),
+            ),
+            frame_colors: FrameColors {
+                common: Rgba([255, 255, 255, 255]),
+                new: Rgba([60, 130, 220, 255]),
+                special: Rgba([210, 40, 60, 255]),
+            },
+            ..Self::default()
+        }
+    }
+
+    pub fn spring() -> Self {
+        Self {
+            background: Background::VerticalGradient(
+                Rgba([255, 228, 236, 255]),
+                Rgba([246, 186, 206, 255]),
+            ),
+            frame_colors: FrameColors {
+                common: Rgba([255, 255, 255, 255]),
+                new: Rgba([120, 190, 120, 255]),
+                special: Rgba([230, 90, 140, 255]),
+            },
+            ..Self::default()
+        }
+    }
+
+    pub fn autumn() -> Self {
+        Self {
+            background: Background::Solid(Rgba([92, 58, 40, 255])),
+            frame_colors: FrameColors {
+                common: Rgba([240, 224, 200, 255]),
+                new: Rgba([250, 170, 60, 255]),
+                special: Rgba([200, 40, 30, 255]),
+            },
+            ..Self::default()
+        }
+    }
+
+    /// The theme for a date, snow in winter, blossoms in spring and maple in autumn
+    pub fn seasonal(date: NaiveDate) -> Self {
+        match date.month() {
+            12 | 1 => Self::winter(),
+            3 | 4 => Self::spring(),
+            10 | 11 => Self::autumn(),
+            _ => Self::default(),
+        }
+    }
+}
+
+pub fn fill_background(canvas: &mut RgbaImage, background: Background) {
+    let height = canvas.height().max(2) as f32;
+    for (_, y, pixel) in canvas.enumerate_pixels_mut() {
+        *pixel = match background {
+            Background::Solid(color) => color,
+            Background::VerticalGradient(top, bottom) => {
+                let t = y as f32 / (height - 1.);
+                Rgba(std::array::from_fn(|c| {
+                    (top[c] as f32 * (1. - t) + bottom[c] as f32 * t).round() as u8
+                }))
+            }
+        };
+    }
+}
+
+/// Signed distance from a pixel center to a rounded rectangle, negative inside
+pub fn rounded_rect_distance(
+    (px, py): (f32, f32),
+    (x, y, width, height): (f32, f32, f32, f32),
+    radius: f32,
+) -> f32 {
+    let radius = radius.min(width / 2.).min(height / 2.);
+    let qx = (px - (x + width / 2.)).abs() - width / 2. + radius;
+    let qy = (py - (y + height / 2.)).abs() - height / 2. + radius;
+    let outside = (qx.max(0.).powi(2) + qy.max(0.).powi(2)).sqrt();
+    outside + qx.max(qy).min(0.) - radius
+}
+
+/// A rounded rectangle whose edge fades out over `softness` px, 1 for plain antialiasing
+pub fn fill_rounded_rect(
+    canvas: &mut RgbaImage,
+    rect: (f32, f32, f32, f32),
+    radius: f32,
+    softness: f32,
+    color: Rgba<u8>,
+) {
+    let (x, y, width, height) = rect;
+    let pad = softness;
+    for py in (y - pad).floor() as i32..(y + height + pad).ceil() as i32 {
+        for px in (x - pad).floor() as i32..(x + width + pad).ceil() as i32 {
+            let d = rounded_rect_distance((px as f32 + 0.5, py as f32 + 0.5), rect, radius);
+            blend(canvas, px, py, color, 0.5 - d / softness.max(1.));
+        }
+    }
+}
+
+/// Make the corners of a tile transparent
+pub fn round_corners(image: &mut RgbaImage, radius: f32) {
+    if radius <= 0. {
+        return;
+    }
+    let rect = (0., 0., image.width() as f32, image.height() as f32);
+    for (x, y, pixel) in image.enumerate_pixels_mut() {
+        let d = rounded_rect_distance((x as f32 + 0.5, y as f32 + 0.5), rect, radius);
+        let coverage = (0.5 - d).clamp(0., 1.);
+        pixel[3] = (pixel[3] as f32 * coverage).round() as u8;
+    }
+}
@@ -1,19 +1,95 @@
-use anyhow::Result;
-use regex::Regex;
+use crate::composite::OutputFormat;
+use crate::composite::label::Corner;
+use crate::layout::LayoutAlgorithm;
+use crate::models::prize::PullSource;
+use anyhow::{Context, Result};
 use std::fs::File;
+use std::str::FromStr;
 use std::sync::LazyLock;
 
 pub const CHANNEL_USERNAME: &str = "WaifuP1c";
 
-pub static CHARACTER_REGEXES: LazyLock<[Regex; 2]> = LazyLock::new(|| {
-    [
-        Regex::new(r"(?:角色|char):\s*#?(\S+)").unwrap(),
-        Regex::new(r"^#\d+\s+#(\S+)").unwrap(),
-    ]
-});
+/// Character extraction rules, see `extractor`
+pub const EXTRACTORS_FILE: &str = "extractors.json";
+
+/// Sources a pull falls back through, e.g. `PULL_FALLBACK_CHAIN=special,channel,local`
+pub static FALLBACK_CHAIN: LazyLock<Vec<PullSource>> = LazyLock::new(|| fallback_chain().unwrap());
+
+fn fallback_chain() -> Result<Vec<PullSource>> {
+    let chain: String = env_or("PULL_FALLBACK_CHAIN", "special,channel,local")?;
+    chain
+        .split(',')
+        .map(|s| s.parse())
+        .collect::<Result<_>>()
+        .context("PULL_FALLBACK_CHAIN invalid")
+}
+
+/// Telegram user allowed to use admin commands and test articles
+pub const ADMIN_USER_ID: i64 = 488811305;
 
 pub const SESSION_FILE: &str = "cuevthbot.session";
 
+/// Downsized images for composites, see `image_cache`
+pub const IMAGE_CACHE_DIR: &str = "image_cache";
+
+/// Size limit of `IMAGE_CACHE_DIR`, e.g. `IMAGE_CACHE_MAX_MB=256`
+pub static IMAGE_CACHE_MAX_BYTES: LazyLock<u64> =
+    LazyLock::new(|| image_cache_max_bytes().unwrap());
+
+fn image_cache_max_bytes() -> Result<u64> {
+    let megabytes: u64 = env_or("IMAGE_CACHE_MAX_MB", "256")?;
+    Ok(megabytes * 1024 * 1024)
+}
+
+/// How ten-pull composites are encoded, e.g. `COMPOSITE_FORMAT=jpeg:60`, `webp:75`,
+/// `webp-lossless` or `png`
+pub static COMPOSITE_FORMAT: LazyLock<OutputFormat> = LazyLock::new(|| composite_format().unwrap());
+
+fn composite_format() -> Result<OutputFormat> {
+    env_or("COMPOSITE_FORMAT", "jpeg:60")
+}
+
+/// Where the numbered badge sits on each composite tile, e.g. `LABEL_CORNER=top-left`,
+/// `top-right`, `bottom-left` or `bottom-right`
+pub static LABEL_CORNER: LazyLock<Corner> = LazyLock::new(|| label_corner().unwrap());
+
+fn label_corner() -> Result<Corner> {
+    env_or("LABEL_CORNER", "top-left")
+}
+
+/// How ten-pull composites are laid out, e.g. `LAYOUT_ALGORITHM=justified`, `optimal`,
+/// `grid:4` or `masonry:3`
+pub static LAYOUT_ALGORITHM: LazyLock<LayoutAlgorithm> =
+    LazyLock::new(|| layout_algorithm().unwrap());
+
+fn layout_algorithm() -> Result<LayoutAlgorithm> {
+    env_or("LAYOUT_ALGORITHM", "justified")
+}
+
+/// The env var `name` parsed, or `default` if it isn't set
+fn env_or<T>(name: &str, default: &str) -> Result<T>
+where
+    T: FromStr,
+    T::Err: Into<anyhow::Error>,
+{
+    let value = std::env::var(name).unwrap_or(default.into());
+    value
+        .parse()
+        .map_err(Into::into)
+        .with_context(|| format!("{name}={value:?} invalid"))
+}
+
+/// Parse the settings above, so a bad value stops startup. Their `LazyLock`s only
+/// unwrap once this has passed, instead of panicking and poisoning in the first pull.
+pub fn init() -> Result<()> {
+    fallback_chain()?;
+    image_cache_max_bytes()?;
+    composite_format()?;
+    label_corner()?;
+    layout_algorithm()?;
+    Ok(())
+}
+
 pub const LOADING_TEXT_FUMO: LazyLock<Vec<String>> = LazyLock::new(|| {
     let result: Result<_> = (|| {
         let file = File::open("fumosays.json")?;
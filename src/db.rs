@@ -1,7 +1,11 @@
-use crate::models::prize::{Prize, PrizePhoto, PrizeSource};
+use crate::models::catalog::ChannelPost;
+use crate::models::character::{Character, Lang};
+use crate::models::prize::{Prize, PrizePhoto, PrizeSource, PullSource};
 use crate::models::user::UserDTO;
+use crate::services::characters::normalize;
 use anyhow::{Context, Result};
 use sqlx::{SqlitePool, sqlite::SqlitePoolOptions};
+use std::collections::HashMap;
 use tracing::instrument;
 
 #[derive(Clone)]
@@ -60,7 +64,12 @@ where
         .map_err(|e| e.into())
     }
 
-    pub async fn update_gacha(&self, user_id: i64, prize: Prize) -> Result<bool> {
+    pub async fn update_gacha(
+        &self,
+        user_id: i64,
+        prize: Prize,
+        source: Option<PullSource>,
+    ) -> Result<bool> {
         let mut tx = self.pool.begin().await?;
 
         let prize_source = match prize.photo {
@@ -88,6 +97,7 @@ where
 
         let now = chrono::Utc::now();
         let prize_json = serde_json::to_string(&prize_source)?;
+        let pull_source = source.map(|source| source.as_str());
 
         let rows = sqlx::query!(
             r#"
@@ -95,12 +105,14 @@ UPDATE users
 SET
     waifu_url = ?,
     last_gacha_time = ?,
-    prize_json = ?
+    prize_json = ?,
+    pull_source = COALESCE(?, pull_source)
 WHERE user_id = ?
             "#,
             prize.url,
             now,
             prize_json,
+            pull_source,
             user_id
         )
         .execute(&mut *tx)
@@ -111,4 +123,480 @@ WHERE user_id = ?
 
         Ok(rows.rows_affected() > 0)
     }
+
+    pub async fn upsert_channel_post(&self, post: &ChannelPost) -> Result<()> {
+        let mut tx = self.pool.begin().await?;
+        let first_character = post.characters.first();
+        sqlx::query!(
+            r#"
+INSERT INTO channel_posts (post_id, character, series, artist, photo_id, posted_at, valid)
+VALUES (?, ?, ?, ?, ?, ?, ?)
+ON CONFLICT (post_id) DO UPDATE SET
+    character = excluded.character,
+    series = excluded.series,
+    artist = excluded.artist,
+    photo_id = excluded.photo_id,
+    posted_at = excluded.posted_at,
+    valid = excluded.valid,
+    indexed_at = CURRENT_TIMESTAMP
+            "#,
+            post.post_id,
+            first_character,
+            post.series,
+            post.artist,
+            post.photo_id,
+            post.posted_at,
+            post.valid,
+        )
+        .execute(&mut *tx)
+        .await?;
+
+        sqlx::query!(
+            r#"DELETE FROM channel_post_characters WHERE post_id = ?"#,
+            post.post_id,
+        )
+        .execute(&mut *tx)
+        .await?;
+        for character in &post.characters {
+            let alias = normalize(character);
+            sqlx::query!(
+                r#"INSERT OR IGNORE INTO channel_post_characters (post_id, character, alias) VALUES (?, ?, ?)"#,
+                post.post_id,
+                character,
+                alias,
+            )
+            .execute(&mut *tx)
+            .await?;
+        }
+        sqlx::query!(
+            r#"DELETE FROM channel_post_failures WHERE post_id = ?"#,
+            post.post_id,
+        )
+        .execute(&mut *tx)
+        .await?;
+
+        tx.commit().await.context("Failed to commit transaction")?;
+        Ok(())
+    }
+
+    pub async fn set_channel_post_valid(&self, post_id: i32, valid: bool) -> Result<()> {
+        sqlx::query!(
+            r#"UPDATE channel_posts SET valid = ? WHERE post_id = ?"#,
+            valid,
+            post_id,
+        )
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    /// Up to `n` distinct valid posts, in random order
+    pub async fn random_channel_posts(&self, n: i64) -> Result<Vec<i32>> {
+        let post_ids = sqlx::query_scalar!(
+            r#"
+SELECT post_id as "post_id: i32"
+FROM channel_posts
+WHERE valid = 1
+ORDER BY RANDOM()
+LIMIT ?
+            "#,
+            n
+        )
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(post_ids)
+    }
+
+    /// Posts with a photo that no extractor rule matched, newest first
+    pub async fn unmatched_channel_posts(&self) -> Result<Vec<i32>> {
+        let post_ids = sqlx::query_scalar!(
+            r#"
+SELECT post_id as "post_id: i32"
+FROM channel_posts
+WHERE photo_id IS NOT NULL AND character IS NULL
+ORDER BY post_id DESC
+            "#
+        )
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(post_ids)
+    }
+
+    /// Remember a post we couldn't index, so a later backfill tries it again
+    pub async fn record_index_failure(&self, post_id: i32, error: &str) -> Result<()> {
+        sqlx::query!(
+            r#"
+INSERT INTO channel_post_failures (post_id, last_error)
+VALUES (?, ?)
+ON CONFLICT (post_id) DO UPDATE SET
+    attempts = attempts + 1,
+    last_error = excluded.last_error,
+    last_attempt_at = CURRENT_TIMESTAMP
+            "#,
+            post_id,
+            error,
+        )
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    /// Posts that failed to index fewer than `max_attempts` times, oldest first
+    pub async fn failed_channel_posts(&self, max_attempts: i64) -> Result<Vec<i32>> {
+        let post_ids = sqlx::query_scalar!(
+            r#"
+SELECT post_id as "post_id: i32"
+FROM channel_post_failures
+WHERE attempts < ?
+ORDER BY post_id
+            "#,
+            max_attempts
+        )
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(post_ids)
+    }
+
+    /// The post is gone, there is nothing left to retry
+    pub async fn clear_index_failure(&self, post_id: i32) -> Result<()> {
+        sqlx::query!(
+            r#"DELETE FROM channel_post_failures WHERE post_id = ?"#,
+            post_id,
+        )
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    pub async fn max_indexed_post_id(&self) -> Result<Option<i32>> {
+        let max_post_id =
+            sqlx::query_scalar!(r#"SELECT MAX(post_id) as "max_post_id: i32" FROM channel_posts"#)
+                .fetch_one(&self.pool)
+                .await?;
+        Ok(max_post_id)
+    }
+
+    /// Credit every character of a pull to the user's collection
+    pub async fn credit_collection(&self, user_id: i64, character_ids: &[i64]) -> Result<()> {
+        let mut tx = self.pool.begin().await?;
+        for character_id in character_ids {
+            sqlx::query!(
+                r#"
+INSERT INTO user_collection (user_id, character_id)
+VALUES (?, ?)
+ON CONFLICT (user_id, character_id) DO UPDATE SET
+    pulls = pulls + 1,
+    last_pulled_at = CURRENT_TIMESTAMP
+                "#,
+                user_id,
+                character_id,
+            )
+            .execute(&mut *tx)
+            .await?;
+        }
+        tx.commit().await.context("Failed to commit transaction")?;
+        Ok(())
+    }
+
+    /// (character_id, pulls), most pulled first
+    pub async fn get_collection(&self, user_id: i64) -> Result<Vec<(i64, i64)>> {
+        let rows = sqlx::query!(
+            r#"
+SELECT character_id, pulls
+FROM user_collection
+WHERE user_id = ?
+ORDER BY pulls DESC, last_pulled_at DESC
+            "#,
+            user_id
+        )
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(rows
+            .into_iter()
+            .map(|row| (row.character_id, row.pulls))
+            .collect())
+    }
+
+    /// Raw names left in the old name-keyed collections
+    pub async fn get_legacy_collection_names(&self) -> Result<Vec<String>> {
+        let names = sqlx::query_scalar!(r#"SELECT DISTINCT character FROM user_characters"#)
+            .fetch_all(&self.pool)
+            .await?;
+        Ok(names)
+    }
+
+    /// Raw catalog names indexed before `channel_post_characters.alias` existed
+    pub async fn get_unaliased_channel_post_characters(&self) -> Result<Vec<String>> {
+        let names = sqlx::query_scalar!(
+            r#"SELECT DISTINCT character FROM channel_post_characters WHERE alias IS NULL"#
+        )
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(names)
+    }
+
+    pub async fn set_channel_post_character_alias(&self, name: &str, alias: &str) -> Result<()> {
+        sqlx::query!(
+            r#"UPDATE channel_post_characters SET alias = ? WHERE character = ?"#,
+            alias,
+            name
+        )
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    /// Move the old collection rows for `name` to `character_id`
+    pub async fn migrate_legacy_collection(&self, name: &str, character_id: i64) -> Result<()> {
+        let mut tx = self.pool.begin().await?;
+        sqlx::query!(
+            r#"
+INSERT INTO user_collection (user_id, character_id, pulls, first_pulled_at, last_pulled_at)
+SELECT user_id, ?, pulls, first_pulled_at, last_pulled_at
+FROM user_characters
+WHERE character = ?
+ON CONFLICT (user_id, character_id) DO UPDATE SET
+    pulls = pulls + excluded.pulls,
+    first_pulled_at = MIN(first_pulled_at, excluded.first_pulled_at),
+    last_pulled_at = MAX(last_pulled_at, excluded.last_pulled_at)
+            "#,
+            character_id,
+            name,
+        )
+        .execute(&mut *tx)
+        .await?;
+        sqlx::query!(r#"DELETE FROM user_characters WHERE character = ?"#, name)
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await.context("Failed to commit transaction")?;
+        Ok(())
+    }
+
+    /// (alias, character) for every alias
+    pub async fn get_character_aliases(&self) -> Result<Vec<(String, Character)>> {
+        let rows = sqlx::query!(
+            r#"
+SELECT
+    a.alias,
+    c.character_id,
+    c.canonical_name,
+    c.name_zh,
+    c.name_ja,
+    c.name_en,
+    c.reading
+FROM
+    character_aliases a
+    JOIN characters c ON c.character_id = a.character_id
+            "#
+        )
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(rows
+            .into_iter()
+            .map(|row| {
+                let character = Character {
+                    id: row.character_id,
+                    canonical_name: row.canonical_name,
+                    name_zh: row.name_zh,
+                    name_ja: row.name_ja,
+                    name_en: row.name_en,
+                    reading: row.reading,
+                };
+                (row.alias, character)
+            })
+            .collect())
+    }
+
+    /// Raw character names from the catalog and collections
+    pub async fn get_all_character_names(&self) -> Result<Vec<String>> {
+        let names = sqlx::query_scalar!(
+            r#"
+SELECT character as "character!" FROM channel_post_characters
+UNION
+SELECT character FROM user_characters
+            "#
+        )
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(names)
+    }
+
+    /// Returns false if the alias already existed. Safe to race on the same alias: the
+    /// character row goes first, so the transaction starts out holding the write lock, and the
+    /// loser's insert is rolled back instead of failing on the alias' UNIQUE constraint.
+    pub async fn create_character(&self, canonical_name: &str, alias: &str) -> Result<bool> {
+        let mut tx = self.pool.begin().await?;
+        let character_id = sqlx::query!(
+            r#"INSERT INTO characters (canonical_name) VALUES (?)"#,
+            canonical_name
+        )
+        .execute(&mut *tx)
+        .await?
+        .last_insert_rowid();
+        let created = sqlx::query!(
+            r#"INSERT INTO character_aliases (alias, character_id) VALUES (?, ?) ON CONFLICT (alias) DO NOTHING"#,
+            alias,
+            character_id
+        )
+        .execute(&mut *tx)
+        .await?
+        .rows_affected()
+            > 0;
+        if !created {
+            tx.rollback().await?;
+            return Ok(false);
+        }
+        tx.commit().await.context("Failed to commit transaction")?;
+        Ok(true)
+    }
+
+    pub async fn merge_characters(&self, from_id: i64, into_id: i64) -> Result<()> {
+        let mut tx = self.pool.begin().await?;
+        sqlx::query!(
+            r#"UPDATE character_aliases SET character_id = ? WHERE character_id = ?"#,
+            into_id,
+            from_id
+        )
+        .execute(&mut *tx)
+        .await?;
+        // Collections follow, pulls of both add up
+        sqlx::query!(
+            r#"
+INSERT INTO user_collection (user_id, character_id, pulls, first_pulled_at, last_pulled_at)
+SELECT user_id, ?, pulls, first_pulled_at, last_pulled_at
+FROM user_collection
+WHERE character_id = ?
+ON CONFLICT (user_id, character_id) DO UPDATE SET
+    pulls = pulls + excluded.pulls,
+    first_pulled_at = MIN(first_pulled_at, excluded.first_pulled_at),
+    last_pulled_at = MAX(last_pulled_at, excluded.last_pulled_at)
+            "#,
+            into_id,
+            from_id
+        )
+        .execute(&mut *tx)
+        .await?;
+        sqlx::query!(
+            r#"DELETE FROM user_collection WHERE character_id = ?"#,
+            from_id
+        )
+        .execute(&mut *tx)
+        .await?;
+        sqlx::query!(r#"DELETE FROM characters WHERE character_id = ?"#, from_id)
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await.context("Failed to commit transaction")?;
+        Ok(())
+    }
+
+    /// Move `alias` to a new character named `canonical_name`
+    pub async fn split_alias(&self, alias: &str, canonical_name: &str) -> Result<()> {
+        let mut tx = self.pool.begin().await?;
+        let character_id = sqlx::query!(
+            r#"INSERT INTO characters (canonical_name) VALUES (?)"#,
+            canonical_name
+        )
+        .execute(&mut *tx)
+        .await?
+        .last_insert_rowid();
+        sqlx::query!(
+            r#"UPDATE character_aliases SET character_id = ? WHERE alias = ?"#,
+            character_id,
+            alias
+        )
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await.context("Failed to commit transaction")?;
+        Ok(())
+    }
+
+    /// Set a localized name and register it as an alias of the same character
+    pub async fn set_character_name(
+        &self,
+        character_id: i64,
+        lang: Lang,
+        name: &str,
+        alias: &str,
+    ) -> Result<()> {
+        let mut tx = self.pool.begin().await?;
+        let query = match lang {
+            Lang::Zh => sqlx::query!(
+                r#"UPDATE characters SET name_zh = ? WHERE character_id = ?"#,
+                name,
+                character_id
+            ),
+            Lang::Ja => sqlx::query!(
+                r#"UPDATE characters SET name_ja = ? WHERE character_id = ?"#,
+                name,
+                character_id
+            ),
+            Lang::En => sqlx::query!(
+                r#"UPDATE characters SET name_en = ? WHERE character_id = ?"#,
+                name,
+                character_id
+            ),
+        };
+        query.execute(&mut *tx).await?;
+        sqlx::query!(
+            r#"INSERT OR REPLACE INTO character_aliases (alias, character_id) VALUES (?, ?)"#,
+            alias,
+            character_id
+        )
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await.context("Failed to commit transaction")?;
+        Ok(())
+    }
+
+    pub async fn set_character_reading(&self, character_id: i64, reading: &str) -> Result<()> {
+        sqlx::query!(
+            r#"UPDATE characters SET reading = ? WHERE character_id = ?"#,
+            reading,
+            character_id
+        )
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    /// One random valid post per character, matching posts through the characters' aliases.
+    /// `aliases`: (character_id, normalized alias). Characters without posts are left out.
+    pub async fn random_channel_post_per_character(
+        &self,
+        aliases: &[(i64, String)],
+    ) -> Result<HashMap<i64, i32>> {
+        let aliases_json = serde_json::to_string(aliases)?;
+        let rows = sqlx::query!(
+            r#"
+WITH
+    wanted AS (
+        SELECT
+            json_extract(value, '$[0]') AS character_id,
+            json_extract(value, '$[1]') AS alias
+        FROM json_each(?)
+    ),
+    ranked AS (
+        SELECT
+            w.character_id,
+            cp.post_id,
+            ROW_NUMBER() OVER (PARTITION BY w.character_id ORDER BY RANDOM()) AS rank
+        FROM
+            wanted w
+            JOIN channel_post_characters cpc ON cpc.alias = w.alias
+            JOIN channel_posts cp ON cp.post_id = cpc.post_id
+        WHERE cp.valid = 1
+    )
+SELECT character_id as "character_id!: i64", post_id as "post_id!: i32"
+FROM ranked
+WHERE rank = 1
+            "#,
+            aliases_json
+        )
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(rows
+            .into_iter()
+            .map(|row| (row.character_id, row.post_id))
+            .collect())
+    }
 }
@@ -0,0 +1,119 @@
+// Shared image downloads: bounded in size and time, and only bytes that really are an
+// image we can decode get through.
+use crate::config::HTTP_CLIENT;
+use anyhow::{Result, anyhow, bail};
+use bytes::Bytes;
+use image::ImageFormat;
+use reqwest::header::CONTENT_TYPE;
+use std::time::Duration;
+
+/// Larger than any photo Telegram or Danbooru previews serve
+pub const MAX_IMAGE_BYTES: usize = 20 * 1024 * 1024;
+
+pub const DOWNLOAD_TIMEOUT: Duration = Duration::from_secs(20);
+
+pub async fn with_timeout<T>(what: &str, fut: impl Future<Output = Result<T>>) -> Result<T> {
+    tokio::time::timeout(DOWNLOAD_TIMEOUT, fut)
+        .await
+        .map_err(|_| anyhow!("Timed out downloading {what}"))?
+}
+
+/// Call before appending `incoming` bytes to `received`
+pub fn ensure_within_limit(received: usize, incoming: usize) -> Result<()> {
+    if received + incoming > MAX_IMAGE_BYTES {
+        bail!("Image larger than {} bytes", MAX_IMAGE_BYTES);
+    }
+    Ok(())
+}
+
+/// Content types lie, look at the bytes. Only formats `image` is built with pass.
+pub fn sniff_image(bytes: &[u8]) -> Result<ImageFormat> {
+    let format = image::guess_format(bytes).map_err(|_| anyhow!("Not an image"))?;
+    match format {
+        ImageFormat::Jpeg | ImageFormat::Png | ImageFormat::WebP => Ok(format),
+        other => Err(anyhow!("Unsupported image format {other:?}")),
+    }
+}
+
+/// Turn a response down before reading its body
+fn check_headers(url: &str, content_length: Option<u64>, content_type: Option<&str>) -> Result<()> {
+    if let Some(length) = content_length {
+        ensure_within_limit(0, length as usize)?;
+    }
+    if let Some(content_type) = content_type
+        && !content_type.starts_with("image/")
+        && !content_type.starts_with("application/octet-stream")
+    {
+        bail!("{url} is {content_type}, not an image");
+    }
+    Ok(())
+}
+
+pub async fn fetch_image(url: &str) -> Result<Bytes> {
+    with_timeout(url, async {
+        let mut response = HTTP_CLIENT.get(url).send().await?.error_for_status()?;
+        let content_type = response
+            .headers()
+            .get(CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok());
+        check_headers(url, response.content_length(), content_type)?;
+        let mut bytes = vec![];
+        while let Some(chunk) = response.chunk().await? {
+            ensure_within_limit(bytes.len(), chunk.len())?;
+            bytes.extend_from_slice(&chunk);
+        }
+        sniff_image(&bytes)?;
+        Ok(bytes.into())
+    })
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const JPEG: &[u8] = &[0xFF, 0xD8, 0xFF, 0xE0, 0x00, 0x10, b'J', b'F', b'I', b'F'];
+    const PNG: &[u8] = b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR";
+    const WEBP: &[u8] = b"RIFF\x24\0\0\0WEBPVP8 ";
+
+    #[test]
+    fn sniff_accepts_decodable_formats() {
+        assert_eq!(sniff_image(JPEG).unwrap(), ImageFormat::Jpeg);
+        assert_eq!(sniff_image(PNG).unwrap(), ImageFormat::Png);
+        assert_eq!(sniff_image(WEBP).unwrap(), ImageFormat::WebP);
+    }
+
+    #[test]
+    fn sniff_rejects_everything_else() {
+        assert!(sniff_image(b"GIF89a\x01\0\x01\0").is_err());
+        assert!(sniff_image(b"<!DOCTYPE html><html>").is_err());
+        assert!(sniff_image(b"").is_err());
+        // A PNG header cut short
+        assert!(sniff_image(&PNG[..4]).is_err());
+    }
+
+    #[test]
+    fn limit_is_inclusive() {
+        assert!(ensure_within_limit(0, MAX_IMAGE_BYTES).is_ok());
+        assert!(ensure_within_limit(0, MAX_IMAGE_BYTES + 1).is_err());
+        // Chunk by chunk, the body hits the limit exactly or goes one over
+        assert!(ensure_within_limit(MAX_IMAGE_BYTES - 10, 10).is_ok());
+        assert!(ensure_within_limit(MAX_IMAGE_BYTES - 10, 11).is_err());
+        assert!(ensure_within_limit(MAX_IMAGE_BYTES, 0).is_ok());
+    }
+
+    #[test]
+    fn headers_reject_large_or_non_image_responses() {
+        let url = "https://example.com/a.jpg";
+        assert!(check_headers(url, None, None).is_ok());
+        assert!(check_headers(url, Some(1024), Some("image/jpeg")).is_ok());
+        assert!(check_headers(url, None, Some("application/octet-stream")).is_ok());
+        assert!(check_headers(url, Some(MAX_IMAGE_BYTES as u64), None).is_ok());
+        assert!(check_headers(url, Some(MAX_IMAGE_BYTES as u64 + 1), Some("image/png")).is_err());
+        let error = check_headers(url, Some(512), Some("text/html; charset=utf-8")).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "https://example.com/a.jpg is text/html; charset=utf-8, not an image"
+        );
+    }
+}
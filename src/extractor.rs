@@ -0,0 +1,366 @@
+// Extract character (and series/artist) names from channel post captions.
+// Rules live in extractors.json, are loaded at startup and can be reloaded with
+// /reload_extractors.
+use crate::config::EXTRACTORS_FILE;
+use anyhow::{Context, Result};
+use grammers_client::message::Message;
+use grammers_tl_types::enums::MessageEntity;
+use regex::Regex;
+use serde::Deserialize;
+use std::fs::File;
+use std::sync::RwLock;
+
+/// Empty until `reload_rules` runs in main
+static RULES: RwLock<Vec<Rule>> = RwLock::new(vec![]);
+
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum RuleConfig {
+    /// A regex with the named groups `character`, and optionally `series` and `artist`.
+    /// A `character` capture like "#A #B" yields both A and B.
+    Regex { name: String, pattern: String },
+    /// Hashtag entities on the same line after each label, e.g. `角色: #A #B`.
+    /// Series and artist take the first hashtag only.
+    Hashtag {
+        name: String,
+        character: String,
+        series: Option<String>,
+        artist: Option<String>,
+    },
+}
+
+enum Rule {
+    Regex {
+        name: String,
+        regex: Regex,
+    },
+    Hashtag {
+        name: String,
+        character: String,
+        series: Option<String>,
+        artist: Option<String>,
+    },
+}
+
+/// Caption text with its hashtags, as byte ranges into `text`
+#[derive(Debug, Default)]
+pub struct Caption {
+    pub text: String,
+    pub hashtags: Vec<(usize, usize)>,
+}
+
+impl Caption {
+    pub fn from_message(msg: &Message) -> Self {
+        let hashtags = msg
+            .fmt_entities()
+            .into_iter()
+            .flatten()
+            .filter_map(|entity| match entity {
+                MessageEntity::Hashtag(hashtag) => Some((hashtag.offset, hashtag.length)),
+                _ => None,
+            });
+        Self::from_utf16_hashtags(msg.text().to_owned(), hashtags)
+    }
+
+    /// Hashtags as (offset, length) in UTF-16 code units, the way Telegram counts them
+    fn from_utf16_hashtags(text: String, hashtags: impl IntoIterator<Item = (i32, i32)>) -> Self {
+        let hashtags = hashtags
+            .into_iter()
+            .filter_map(|(offset, length)| {
+                let start = utf16_to_byte_offset(&text, offset as usize)?;
+                let end = utf16_to_byte_offset(&text, (offset + length) as usize)?;
+                Some((start, end))
+            })
+            .collect();
+        Self { text, hashtags }
+    }
+
+    /// For captions scraped from t.me, where we only know the hashtag texts
+    pub fn from_text_and_hashtags(text: String, hashtags: &[String]) -> Self {
+        let mut ranges = vec![];
+        let mut search_from = 0;
+        for hashtag in hashtags {
+            let needle = format!("#{hashtag}");
+            if let Some(pos) = text[search_from..].find(&needle) {
+                let start = search_from + pos;
+                ranges.push((start, start + needle.len()));
+                search_from = start + needle.len();
+            }
+        }
+        Self {
+            text,
+            hashtags: ranges,
+        }
+    }
+
+    /// Hashtags after `label` on the same line, without '#'
+    fn hashtags_after(&self, label: &str) -> Vec<String> {
+        let Some(label_pos) = self.text.find(label).map(|pos| pos + label.len()) else {
+            return vec![];
+        };
+        let line_end = self.text[label_pos..]
+            .find('\n')
+            .map_or(self.text.len(), |pos| label_pos + pos);
+        self.hashtags
+            .iter()
+            .filter(|&&(start, _)| start >= label_pos && start < line_end)
+            .map(|&(start, end)| self.text[start..end].trim_start_matches('#').to_owned())
+            .collect()
+    }
+
+    fn hashtag_after(&self, label: &str) -> Option<String> {
+        self.hashtags_after(label).into_iter().next()
+    }
+}
+
+fn utf16_to_byte_offset(text: &str, utf16_offset: usize) -> Option<usize> {
+    let mut utf16_count = 0;
+    for (byte_offset, ch) in text.char_indices() {
+        if utf16_count == utf16_offset {
+            return Some(byte_offset);
+        }
+        utf16_count += ch.len_utf16();
+    }
+    (utf16_count == utf16_offset).then_some(text.len())
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Extracted {
+    /// Never empty
+    pub characters: Vec<String>,
+    pub series: Option<String>,
+    pub artist: Option<String>,
+    /// Name of the rule that matched
+    pub rule: String,
+}
+
+fn load_rules() -> Result<Vec<Rule>> {
+    let file = File::open(EXTRACTORS_FILE).context("Failed to open extractor rules")?;
+    let configs: Vec<RuleConfig> =
+        serde_json::from_reader(file).context("Failed to parse extractor rules")?;
+    build_rules(configs)
+}
+
+fn build_rules(configs: Vec<RuleConfig>) -> Result<Vec<Rule>> {
+    configs
+        .into_iter()
+        .map(|config| {
+            Ok(match config {
+                RuleConfig::Regex { name, pattern } => {
+                    let regex = Regex::new(&pattern)
+                        .with_context(|| format!("Invalid regex in rule '{name}'"))?;
+                    if !regex
+                        .capture_names()
+                        .any(|group| group == Some("character"))
+                    {
+                        anyhow::bail!("Rule '{name}' has no `character` group");
+                    }
+                    Rule::Regex { name, regex }
+                }
+                RuleConfig::Hashtag {
+                    name,
+                    character,
+                    series,
+                    artist,
+                } => Rule::Hashtag {
+                    name,
+                    character,
+                    series,
+                    artist,
+                },
+            })
+        })
+        .collect()
+}
+
+/// Reload extractors.json, keeping the current rules if it is invalid
+pub fn reload_rules() -> Result<usize> {
+    let rules = load_rules()?;
+    let count = rules.len();
+    *RULES.write().unwrap() = rules;
+    Ok(count)
+}
+
+/// "#A #B" -> [A, B], anything else is a single name
+fn split_names(captured: &str) -> Vec<String> {
+    let captured = captured.trim();
+    if captured.matches('#').count() > 1 || captured.starts_with('#') {
+        captured
+            .split('#')
+            .map(|name| name.trim())
+            .filter(|name| !name.is_empty())
+            .map(ToOwned::to_owned)
+            .collect()
+    } else {
+        vec![captured.to_owned()]
+    }
+}
+
+/// Try each rule in order, the first match wins
+pub fn extract(caption: &Caption) -> Option<Extracted> {
+    extract_with(&RULES.read().unwrap(), caption)
+}
+
+fn extract_with(rules: &[Rule], caption: &Caption) -> Option<Extracted> {
+    rules.iter().find_map(|rule| match rule {
+        Rule::Regex { name, regex } => {
+            let captures = regex.captures(&caption.text)?;
+            let group = |group: &str| captures.name(group).map(|m| m.as_str().to_owned());
+            let characters = split_names(&group("character")?);
+            if characters.is_empty() {
+                return None;
+            }
+            Some(Extracted {
+                characters,
+                series: group("series"),
+                artist: group("artist"),
+                rule: name.clone(),
+            })
+        }
+        Rule::Hashtag {
+            name,
+            character,
+            series,
+            artist,
+        } => Some(Extracted {
+            characters: Some(caption.hashtags_after(character)).filter(|c| !c.is_empty())?,
+            series: series
+                .as_deref()
+                .and_then(|label| caption.hashtag_after(label)),
+            artist: artist
+                .as_deref()
+                .and_then(|label| caption.hashtag_after(label)),
+            rule: name.clone(),
+        }),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rules() -> Vec<Rule> {
+        let configs = serde_json::from_str(include_str!("../extractors.json")).unwrap();
+        build_rules(configs).unwrap()
+    }
+
+    fn caption(text: &str, hashtags: &[&str]) -> Caption {
+        let hashtags = hashtags.iter().map(|&h| h.to_owned()).collect::<Vec<_>>();
+        Caption::from_text_and_hashtags(text.to_owned(), &hashtags)
+    }
+
+    /// Rule, characters, series and artist
+    type Expected = (
+        &'static str,
+        &'static [&'static str],
+        Option<&'static str>,
+        Option<&'static str>,
+    );
+
+    #[test]
+    fn extract_table() {
+        let rules = rules();
+        let cases: &[(&str, &[&str], Option<Expected>)] = &[
+            (
+                "角色: #博丽灵梦 #雾雨魔理沙\n作品: #东方Project\n画师: #ZUN",
+                &["博丽灵梦", "雾雨魔理沙", "东方Project", "ZUN"],
+                Some((
+                    "labelled hashtags",
+                    &["博丽灵梦", "雾雨魔理沙"],
+                    Some("东方Project"),
+                    Some("ZUN"),
+                )),
+            ),
+            // Hashtags on the next line belong to another label
+            (
+                "角色: #Reimu\n#Marisa",
+                &["Reimu", "Marisa"],
+                Some(("labelled hashtags", &["Reimu"], None, None)),
+            ),
+            // Scraped without hashtags, the labelled regex takes over
+            (
+                "char: #Reimu #Marisa\nsomething else",
+                &[],
+                Some(("labelled", &["Reimu", "Marisa"], None, None)),
+            ),
+            (
+                "角色:Cirno",
+                &[],
+                Some(("labelled", &["Cirno"], None, None)),
+            ),
+            (
+                "#1234 #Sakuya extra",
+                &["1234", "Sakuya"],
+                Some(("leading post number", &["Sakuya"], None, None)),
+            ),
+            ("Just a picture", &[], None),
+            ("#art #daily", &["art", "daily"], None),
+        ];
+        for &(text, hashtags, expected) in cases {
+            let extracted = extract_with(&rules, &caption(text, hashtags));
+            let expected = expected.map(|(rule, characters, series, artist)| Extracted {
+                characters: characters.iter().map(|&c| c.to_owned()).collect(),
+                series: series.map(ToOwned::to_owned),
+                artist: artist.map(ToOwned::to_owned),
+                rule: rule.to_owned(),
+            });
+            assert_eq!(extracted, expected, "{text:?}");
+        }
+    }
+
+    #[test]
+    fn rules_need_a_character_group() {
+        let configs =
+            serde_json::from_str(r#"[{"type": "regex", "name": "x", "pattern": "(?P<name>.+)"}]"#)
+                .unwrap();
+        assert!(build_rules(configs).is_err());
+        let configs =
+            serde_json::from_str(r#"[{"type": "regex", "name": "x", "pattern": "("}]"#).unwrap();
+        assert!(build_rules(configs).is_err());
+    }
+
+    #[test]
+    fn split_names_table() {
+        let cases: &[(&str, &[&str])] = &[
+            ("#A #B", &["A", "B"]),
+            ("#A", &["A"]),
+            (" A ", &["A"]),
+            ("A#B", &["A#B"]),
+            ("#A  # #B", &["A", "B"]),
+            ("#", &[]),
+        ];
+        for &(captured, expected) in cases {
+            assert_eq!(split_names(captured), expected, "{captured:?}");
+        }
+    }
+
+    #[test]
+    fn hashtags_after_stay_on_the_label_line() {
+        let caption = caption("#0 角色: #A #B\n作品: #S #T", &["0", "A", "B", "S", "T"]);
+        assert_eq!(caption.hashtags_after("角色:"), ["A", "B"]);
+        assert_eq!(caption.hashtags_after("作品:"), ["S", "T"]);
+        assert_eq!(caption.hashtag_after("作品:").as_deref(), Some("S"));
+        assert!(caption.hashtags_after("画师:").is_empty());
+    }
+
+    #[test]
+    fn utf16_offsets_with_cjk_and_emoji() {
+        // "🌸" is two UTF-16 units and four bytes, "角" is one unit and three bytes
+        let text = "🌸角 #灵梦 #A😀 #B";
+        assert_eq!(utf16_to_byte_offset(text, 0), Some(0));
+        assert_eq!(utf16_to_byte_offset(text, 2), Some(4));
+        assert_eq!(utf16_to_byte_offset(text, 3), Some(7));
+        // Inside the surrogate pair
+        assert_eq!(utf16_to_byte_offset(text, 1), None);
+        assert_eq!(utf16_to_byte_offset(text, 15), Some(text.len()));
+        assert_eq!(utf16_to_byte_offset(text, 16), None);
+
+        let caption = Caption::from_utf16_hashtags(text.to_owned(), [(4, 3), (8, 2), (13, 2)]);
+        let hashtags = caption
+            .hashtags
+            .iter()
+            .map(|&(start, end)| &caption.text[start..end])
+            .collect::<Vec<_>>();
+        assert_eq!(hashtags, ["#灵梦", "#A", "#B"]);
+    }
+}
@@ -1,21 +1,43 @@
-use crate::config::LOADING_TEXT_FUMO;
-use crate::models::prize::PrizePhoto;
-use crate::services::gacha::{single_pull, ten_pulls};
-use crate::store::STORE;
+use crate::config::{ADMIN_USER_ID, CHANNEL_USERNAME, LOADING_TEXT_FUMO};
+use crate::extractor;
+use crate::models::character::{Character, Lang};
+use crate::models::prize::{PhotoVariant, Prize, PrizePhoto};
+use crate::services::catalog;
+use crate::services::characters;
+use crate::services::danbooru::DanbooruError;
+use crate::services::gacha::{
+    CharacterNotFound, PullFailed, character_prizes, pull_character, single_pull, ten_pulls,
+};
+use crate::services::health::HEALTH;
+use crate::services::search;
+use crate::store::{STORE, WAIFU_CHANNEL_ID, today_prize};
 use anyhow::{Result, anyhow};
 use grammers_client::Client;
 use grammers_client::message::{Button, InputMessage, ReplyMarkup};
 use grammers_client::update::{Article, Update};
+use grammers_session::types::PeerId;
+use grammers_tl_types as tl;
 use rand::prelude::*;
 use rand::rng;
+use std::sync::Arc;
 
 pub async fn handle_update(client: Client, update: Update) -> Result<()> {
     tracing::debug!(?update);
     match update {
-        Update::NewMessage(message) if message.text() == "/start" => {
-            message
-                .respond(InputMessage::new().text("I'm alive!"))
-                .await?;
+        Update::NewMessage(message) | Update::MessageEdited(message)
+            if is_waifu_channel(message.peer_id()) =>
+        {
+            catalog::on_channel_post(&message).await?;
+        }
+        Update::MessageDeleted(deletion) => {
+            if let Some(channel_id) = deletion.channel_id()
+                && is_waifu_channel_bare_id(channel_id)
+            {
+                catalog::on_channel_posts_deleted(deletion.messages()).await?;
+            }
+        }
+        Update::NewMessage(message) if message.text().starts_with('/') => {
+            handle_command(client, message).await?;
         }
         Update::InlineQuery(query) => {
             handle_inline_query(query).await?;
@@ -31,58 +53,403 @@ pub async fn handle_update(client: Client, update: Update) -> Result<()> {
     Ok(())
 }
 
+#[tracing::instrument(skip(client, message))]
+async fn handle_command(client: Client, message: grammers_client::message::Message) -> Result<()> {
+    // "/cmd@cuevthbot args" -> ("cmd", "args")
+    let text = message.text().trim();
+    let (command, args) = text.split_once(char::is_whitespace).unwrap_or((text, ""));
+    let command = command[1..].split('@').next().unwrap_or_default();
+    let args = args.trim();
+
+    let is_admin = message
+        .sender()
+        .is_some_and(|sender| sender.id().bare_id() == ADMIN_USER_ID);
+
+    let reply = match command {
+        "start" => "I'm alive!".to_owned(),
+        "status" if is_admin => HEALTH.report(),
+        "pull" => {
+            let Some(sender) = message.sender() else {
+                return Ok(());
+            };
+            let Some(character) = search::search(args, 1).pop() else {
+                message
+                    .respond(InputMessage::new().text(if args.is_empty() {
+                        "Usage: /pull <角色名>".to_owned()
+                    } else {
+                        format!("找不到 {args}，换个名字试试")
+                    }))
+                    .await?;
+                return Ok(());
+            };
+            let reply = match pull_character(&character).await {
+                Ok(prize) => {
+                    credit_prize(sender.id().bare_id(), &prize).await;
+                    let message_text = format!(
+                        "亲爱的[{}](tg://user?id={})\\\n你要的老婆 {}",
+                        sender.name().unwrap_or(""),
+                        sender.id().bare_id(),
+                        prize.markdown_names(),
+                    );
+                    let input_message = InputMessage::new().markdown(message_text);
+                    with_photo(&client, input_message, prize.photo).await?
+                }
+                Err(e) => {
+                    tracing::warn!("Failed to pull {}: {:#}", character.display_name(), e);
+                    InputMessage::new().text(user_facing_error(&e))
+                }
+            };
+            message.respond(reply).await?;
+            return Ok(());
+        }
+        "search" => {
+            let found = search::search(args, 10);
+            if found.is_empty() {
+                format!("找不到 {args}，换个名字试试")
+            } else {
+                let mut reply = String::new();
+                for character in found {
+                    reply.push_str(&format!(
+                        "{} ({})\n",
+                        character.display_name(),
+                        characters::aliases_of(character.id).join(", ")
+                    ));
+                }
+                reply
+            }
+        }
+        "collection" => {
+            let Some(user_id) = message.sender().map(|sender| sender.id().bare_id()) else {
+                return Ok(());
+            };
+            let collection = STORE
+                .get()
+                .await?
+                .database()
+                .get_collection(user_id)
+                .await?;
+            if collection.is_empty() {
+                "你还没有老婆，快去抽一个吧".to_owned()
+            } else {
+                let mut reply = format!("你一共抽到过 {} 位老婆\n", collection.len());
+                for (character_id, pulls) in collection.iter().take(30) {
+                    // Merged away since, the pulls moved to another id
+                    let Some(character) = characters::character_by_id(*character_id) else {
+                        continue;
+                    };
+                    reply.push_str(&format!("{} ×{pulls}\n", character.display_name()));
+                }
+                reply
+            }
+        }
+        "alias" if is_admin => match characters::character_of(args) {
+            Some(character) => describe_character(&character),
+            None => format!("Unknown alias {args}"),
+        },
+        "alias_merge" if is_admin => match args.split_whitespace().collect::<Vec<_>>()[..] {
+            [alias, target] => match characters::merge(alias, target).await {
+                Ok(character) => describe_character(&character),
+                Err(e) => e.to_string(),
+            },
+            _ => "Usage: /alias_merge <alias> <target>".to_owned(),
+        },
+        "alias_split" if is_admin => match characters::split(args).await {
+            Ok(character) => describe_character(&character),
+            Err(e) => e.to_string(),
+        },
+        "alias_name" if is_admin => match args.split_whitespace().collect::<Vec<_>>()[..] {
+            [alias, lang, name] => match Lang::parse(lang) {
+                Some(lang) => match characters::set_name(alias, lang, name).await {
+                    Ok(character) => describe_character(&character),
+                    Err(e) => e.to_string(),
+                },
+                None => "Language must be one of zh, ja, en".to_owned(),
+            },
+            _ => "Usage: /alias_name <alias> <zh|ja|en> <name>".to_owned(),
+        },
+        "alias_reading" if is_admin => match args.split_once(char::is_whitespace) {
+            Some((alias, reading)) if !reading.trim().is_empty() => {
+                match characters::set_reading(alias, reading).await {
+                    Ok(character) => describe_character(&character),
+                    Err(e) => e.to_string(),
+                }
+            }
+            _ => "Usage: /alias_reading <alias> <kana reading>".to_owned(),
+        },
+        "unmatched" if is_admin => {
+            let post_ids = STORE
+                .get()
+                .await?
+                .database()
+                .unmatched_channel_posts()
+                .await?;
+            let mut reply = format!("{} posts matched no extractor rule\n", post_ids.len());
+            for post_id in post_ids.iter().take(20) {
+                reply.push_str(&format!("https://t.me/{CHANNEL_USERNAME}/{post_id}\n"));
+            }
+            reply
+        }
+        "reload_extractors" if is_admin => match extractor::reload_rules() {
+            Ok(count) => match catalog::reindex_unmatched().await {
+                Ok(reindexed) => format!(
+                    "Loaded {count} rules, {} posts matched now, {} still unmatched, \
+                     {} deleted, {} failed",
+                    reindexed.matched, reindexed.unmatched, reindexed.deleted, reindexed.failed
+                ),
+                Err(e) => format!("Loaded {count} rules, reindexing stopped: {e:#}"),
+            },
+            // Keeps the current rules, tell the admin what is wrong with the file
+            Err(e) => format!("{e:#}"),
+        },
+        _ => return Ok(()),
+    };
+    message.respond(InputMessage::new().text(reply)).await?;
+    Ok(())
+}
+
+fn describe_character(character: &Character) -> String {
+    format!(
+        "#{} {}\nzh: {}\nja: {}\nen: {}\nreading: {}\naliases: {}",
+        character.id,
+        character.canonical_name,
+        character.name_zh.as_deref().unwrap_or("-"),
+        character.name_ja.as_deref().unwrap_or("-"),
+        character.name_en.as_deref().unwrap_or("-"),
+        character.reading.as_deref().unwrap_or("-"),
+        characters::aliases_of(character.id).join(", "),
+    )
+}
+
+fn is_waifu_channel(peer_id: PeerId) -> bool {
+    WAIFU_CHANNEL_ID.get() == Some(&peer_id)
+}
+
+fn is_waifu_channel_bare_id(channel_id: i64) -> bool {
+    WAIFU_CHANNEL_ID
+        .get()
+        .is_some_and(|id| id.bare_id() == channel_id)
+}
+
+/// Search results per page of inline results
+const INLINE_PAGE_SIZE: usize = 20;
+
 #[tracing::instrument(skip(query))]
 async fn handle_inline_query(query: grammers_client::update::InlineQuery) -> Result<()> {
-    if let Some(sender) = query.sender() {
-        let sender_id = sender.id().bare_id();
+    let Some(sender) = query.sender() else {
+        return Ok(());
+    };
+    let sender_id = sender.id().bare_id();
 
-        let mut store = STORE.get().await?;
-        let _ = store.get_user_info_or_create(sender_id);
+    let today = today_prize(sender_id).await?;
 
-        let thumb_url = "https://img.icons8.com/ios/150/FFFFFF/gift--v1.png";
+    let thumb_url = "https://img.icons8.com/ios/150/FFFFFF/gift--v1.png";
 
-        let fumo_says = LOADING_TEXT_FUMO
-            .choose(&mut rng())
-            .cloned()
-            .unwrap_or("Loading".into());
-        let mut answer_list = vec![];
-        let button = [Button::data("🔮 在路上", b"loading")];
-        {
-            let msg = InputMessage::new()
-                .text(&fumo_says)
-                .reply_markup(ReplyMarkup::from_buttons_row(&button));
-            let answer = Article::new("每日老婆", msg)
-                .id("single_pull")
-                .thumb_url(thumb_url);
-            answer_list.push(answer);
-        }
+    let fumo_says = LOADING_TEXT_FUMO
+        .choose(&mut rng())
+        .cloned()
+        .unwrap_or("Loading".into());
+    let button = [Button::data("🔮 在路上", b"loading")];
+    let placeholder = || {
+        InputMessage::new()
+            .text(&fumo_says)
+            .reply_markup(ReplyMarkup::from_buttons_row(&button))
+    };
 
-        {
-            let msg = InputMessage::new()
-                .text(&fumo_says)
-                .reply_markup(ReplyMarkup::from_buttons_row(&button));
-            let answer = Article::new("十连 (WIP)", msg)
+    // The offset is the index of the first search result on the page
+    let offset = query.offset().parse::<usize>().unwrap_or(0);
+    let mut answer_list: Vec<tl::enums::InputBotInlineResult> = vec![];
+
+    // Pinned on the first page
+    if offset == 0 {
+        // Already pulled today, send the photo right away
+        let daily_photo = today.as_ref().and_then(|prize| {
+            let caption = daily_caption(&sender.full_name(), sender_id, prize);
+            photo_result("today".to_owned(), prize, caption)
+        });
+        let daily = match (&today, daily_photo) {
+            (_, Some(result)) => result,
+            // A photo we can't send inline, edit the placeholder on send as usual
+            (Some(prize), None) => {
+                let name = prize.display_name();
+                let msg = InputMessage::new()
+                    .text(format!("正在请 {name} 出来…"))
+                    .reply_markup(ReplyMarkup::from_buttons_row(&button));
+                Article::new("每日老婆", msg)
+                    .id("single_pull")
+                    .description(format!("今天已经抽过了：{name}"))
+                    .thumb_url(thumb_url)
+                    .into()
+            }
+            (None, None) => Article::new("每日老婆", placeholder())
+                .id("single_pull")
+                .thumb_url(thumb_url)
+                .into(),
+        };
+        answer_list.push(daily);
+        answer_list.push(
+            Article::new("十连 (WIP)", placeholder())
                 .id("ten_pulls")
                 .description("可能会抽到奇怪的东西（？）")
-                .thumb_url(thumb_url);
-            answer_list.push(answer);
+                .thumb_url(thumb_url)
+                .into(),
+        );
+        if sender_id == ADMIN_USER_ID {
+            answer_list.push(
+                Article::new("test", placeholder())
+                    .id(format!("test{}", rand::random_range(0i32..=1000)))
+                    .thumb_url(thumb_url)
+                    .into(),
+            );
         }
+    }
 
-        if sender_id == 488811305 {
-            let msg = InputMessage::new()
-                .text(fumo_says)
-                .reply_markup(ReplyMarkup::from_buttons_row(&button));
-            let answer = Article::new("test", msg)
-                .id(format!("test{}", rand::random_range(0i32..=1000)))
-                .thumb_url(thumb_url);
-            answer_list.push(answer);
+    // One more than a page, to know whether there is a next page
+    let found = match query.text().trim() {
+        "收藏" | "collection" => collection_characters(sender_id).await?,
+        text => search::search(text, offset + INLINE_PAGE_SIZE + 1),
+    };
+    let has_next_page = found.len() > offset + INLINE_PAGE_SIZE;
+    let page = found
+        .into_iter()
+        .skip(offset)
+        .take(INLINE_PAGE_SIZE)
+        .collect::<Vec<_>>();
+    let prizes = character_prizes(&page).await?;
+    for (character, prize) in page.iter().zip(prizes) {
+        let result = prize.and_then(|(post_id, prize)| {
+            let caption = prize.linked_names();
+            photo_result(format!("post:{post_id}:{}", character.id), &prize, caption)
+        });
+        match result {
+            Some(result) => answer_list.push(result),
+            // Not in the catalog yet, pull it on send
+            None => answer_list.push(
+                Article::new(format!("抽 {}", character.display_name()), placeholder())
+                    .id(format!("character:{}", character.id))
+                    .description("从频道里随机挑一张")
+                    .thumb_url(thumb_url)
+                    .into(),
+            ),
         }
+    }
 
-        query.answer(answer_list).send().await?;
+    // Pinned results differ for the admin, and search results pick a random post each time
+    let mut answer = query.answer(answer_list).private().cache_time(10);
+    if has_next_page {
+        answer = answer.next_offset((offset + INLINE_PAGE_SIZE).to_string());
     }
+    answer.send().await?;
     Ok(())
 }
 
+/// Characters in the user's collection, most pulled first
+async fn collection_characters(user_id: i64) -> Result<Vec<Arc<Character>>> {
+    let collection = STORE
+        .get()
+        .await?
+        .database()
+        .get_collection(user_id)
+        .await?;
+    Ok(collection
+        .into_iter()
+        .filter_map(|(character_id, _)| characters::character_by_id(character_id))
+        .collect())
+}
+
+/// "亲爱的<sender>\n今天的老婆是 <names>" as text and entities, like the edited placeholder
+fn daily_caption(
+    sender_name: &str,
+    sender_id: i64,
+    prize: &Prize,
+) -> (String, Vec<tl::enums::MessageEntity>) {
+    let mut text = "亲爱的".to_owned();
+    let mut entities = vec![tl::enums::MessageEntity::TextUrl(
+        tl::types::MessageEntityTextUrl {
+            offset: text.encode_utf16().count() as i32,
+            length: sender_name.encode_utf16().count() as i32,
+            url: format!("tg://user?id={sender_id}"),
+        },
+    )];
+    text.push_str(sender_name);
+    text.push_str("\n今天的老婆是 ");
+    prize.push_linked_names(&mut text, &mut entities);
+    (text, entities)
+}
+
+/// An inline result that is a complete photo message when sent, no placeholder to edit.
+/// None for photos we would have to upload first.
+fn photo_result(
+    id: String,
+    prize: &Prize,
+    (caption, entities): (String, Vec<tl::enums::MessageEntity>),
+) -> Option<tl::enums::InputBotInlineResult> {
+    let send_message = tl::types::InputBotInlineMessageMediaAuto {
+        invert_media: false,
+        message: caption,
+        entities: Some(entities),
+        reply_markup: None,
+    }
+    .into();
+    match &prize.photo {
+        PrizePhoto::TelegramPhoto(photo) => {
+            let tl::enums::Photo::Photo(raw) = photo.raw.photo.as_ref()? else {
+                return None;
+            };
+            Some(
+                tl::types::InputBotInlineResultPhoto {
+                    id,
+                    r#type: "photo".to_owned(),
+                    photo: tl::types::InputPhoto {
+                        id: raw.id,
+                        access_hash: raw.access_hash,
+                        file_reference: raw.file_reference.clone(),
+                    }
+                    .into(),
+                    send_message,
+                }
+                .into(),
+            )
+        }
+        PrizePhoto::Url(_) => {
+            // Inline photos by URL must be JPEG. Danbooru's 720x720 is WebP, its sample and
+            // 360x360 are JPEG. Without one, the caller falls back to the article.
+            let mut jpegs = prize
+                .variants
+                .iter()
+                .filter(|variant| variant.file_ext == "jpg" || variant.file_ext == "jpeg")
+                .collect::<Vec<_>>();
+            jpegs.sort_by_key(|variant| variant.width as u64 * variant.height as u64);
+            let document = |variant: &PhotoVariant| tl::types::InputWebDocument {
+                url: variant.url.clone(),
+                // Danbooru doesn't report variant file sizes, 0 is unknown
+                size: 0,
+                mime_type: "image/jpeg".to_owned(),
+                attributes: vec![
+                    tl::types::DocumentAttributeImageSize {
+                        w: variant.width as i32,
+                        h: variant.height as i32,
+                    }
+                    .into(),
+                ],
+            };
+            Some(
+                tl::types::InputBotInlineResult {
+                    id,
+                    r#type: "photo".to_owned(),
+                    title: None,
+                    description: None,
+                    url: None,
+                    thumb: Some(document(jpegs.first()?).into()),
+                    content: Some(document(jpegs.last()?).into()),
+                    send_message,
+                }
+                .into(),
+            )
+        }
+        PrizePhoto::File { .. } => None,
+    }
+}
+
 #[tracing::instrument(skip(client, query))]
 async fn handle_inline_send(
     client: Client,
@@ -94,6 +461,25 @@ async fn handle_inline_send(
     let sender_name = sender.full_name();
     let sender_id = sender.id().bare_id();
 
+    // Photo results are complete messages already. Today's prize was credited when it was
+    // pulled, a search result is credited now.
+    if query.result_id() == "today" {
+        return Ok(());
+    }
+    if let Some(rest) = query.result_id().strip_prefix("post:") {
+        if let Some(post_id) = rest.split(':').next().and_then(|id| id.parse().ok()) {
+            let prize = STORE
+                .get()
+                .await?
+                .get_prize_from_channel_post(post_id)
+                .await?;
+            if let Some(prize) = prize {
+                credit_prize(sender_id, &prize).await;
+            }
+        }
+        return Ok(());
+    }
+
     let (user, maybe_prize) = {
         let mut store = STORE.get().await?;
         let user = store.get_user_info_or_create(sender_id).await?;
@@ -105,62 +491,82 @@ async fn handle_inline_send(
         (user, maybe_prize)
     };
 
-    let input_message: InputMessage;
-    let photo: PrizePhoto;
-
-    match query.result_id() {
-        "single_pull" => {
-            tracing::info!(user_id = sender_id, "Processing inline send (single_pull)");
-            let prize = if let Some(prize0) = maybe_prize {
-                prize0
-            } else {
-                single_pull(&user).await?
-            };
-            STORE
-                .get()
-                .await?
-                .update_user_gacha(sender_id, prize.clone())
-                .await?;
+    let result: Result<(InputMessage, PrizePhoto)> = async {
+        match query.result_id() {
+            "single_pull" => {
+                tracing::info!(user_id = sender_id, "Processing inline send (single_pull)");
+                let (prize, source) = if let Some(prize0) = maybe_prize {
+                    (prize0, None)
+                } else {
+                    let (source, prize) = single_pull(&user).await?;
+                    tracing::info!(
+                        user_id = sender_id,
+                        ?source,
+                        "Pulled {}",
+                        prize.display_name()
+                    );
+                    credit_prize(sender_id, &prize).await;
+                    (prize, Some(source))
+                };
+                STORE
+                    .get()
+                    .await?
+                    .update_user_gacha(sender_id, prize.clone(), source)
+                    .await?;
 
-            // From grammers-client/src/parsers/markdown.rs:
-            // Parse a message containing CommonMark-flavored markdown into plain text and the list of formatting entities understood by Telegram.
-            // This is not the same as the markdown understood by Telegram's HTTP Bot API.
-            //
-            // so use \\\n to insert a line break
-            let message_text = format!(
-                "亲爱的[{}](tg://user?id={})\\\n今天的老婆是 [{}]({})",
-                sender_name, sender_id, prize.name, prize.url,
-            );
-            input_message = InputMessage::new().markdown(message_text);
-            photo = prize.photo;
-        }
-        "ten_pulls" => {
-            tracing::info!(user_id = sender_id, "Processing inline send (ten_pulls)");
-            (input_message, photo) = ten_pulls(&user).await?;
+                // From grammers-client/src/parsers/markdown.rs:
+                // Parse a message containing CommonMark-flavored markdown into plain text and the list of formatting entities understood by Telegram.
+                // This is not the same as the markdown understood by Telegram's HTTP Bot API.
+                //
+                // so use \\\n to insert a line break
+                let message_text = format!(
+                    "亲爱的[{}](tg://user?id={})\\\n今天的老婆是 {}",
+                    sender_name,
+                    sender_id,
+                    prize.markdown_names(),
+                );
+                Ok((InputMessage::new().markdown(message_text), prize.photo))
+            }
+            "ten_pulls" => {
+                tracing::info!(user_id = sender_id, "Processing inline send (ten_pulls)");
+                ten_pulls(&user).await
+            }
+            result_id if result_id.starts_with("character:") => {
+                tracing::info!(user_id = sender_id, "Processing inline send ({result_id})");
+                let character_id = &result_id["character:".len()..];
+                let character = character_id
+                    .parse()
+                    .ok()
+                    .and_then(characters::character_by_id)
+                    .ok_or(anyhow!("Unknown character {character_id}"))?;
+                let prize = pull_character(&character).await?;
+                credit_prize(sender_id, &prize).await;
+                let message_text = format!(
+                    "亲爱的[{}](tg://user?id={})\\\n你要的老婆 {}",
+                    sender_name,
+                    sender_id,
+                    prize.markdown_names(),
+                );
+                Ok((InputMessage::new().markdown(message_text), prize.photo))
+            }
+            _ => Err(anyhow!("unexpected msg_id")),
         }
-        _ => return Err(anyhow!("unexpected msg_id")),
-    };
+    }
+    .await;
 
-    match photo {
-        PrizePhoto::File {
-            name: filename,
-            content,
-        } => {
-            let len = content.len();
-            let mut cursor = std::io::Cursor::new(content);
-            let uploaded = client.upload_stream(&mut cursor, len, filename).await?;
-            query.edit_message(input_message.photo(uploaded)).await?;
-        }
-        PrizePhoto::Url(photo_url) => {
+    let (input_message, photo) = match result {
+        Ok(x) => x,
+        Err(e) => {
+            // Replace the loading placeholder, otherwise it spins forever
             query
-                .edit_message(input_message.photo_url(photo_url))
+                .edit_message(InputMessage::new().text(user_facing_error(&e)))
                 .await?;
+            return Err(e);
         }
-        PrizePhoto::TelegramPhoto(photo) => {
-            let photo = photo.clone().into();
-            query.edit_message(input_message.copy_media(&photo)).await?;
-        }
-    }
+    };
+
+    let input_message = with_photo(&client, input_message, photo).await?;
+    query.edit_message(input_message).await?;
 
     Ok(())
 }
@@ -196,39 +602,92 @@ async fn handle_callback_query(
         "Processing callback query (ten pull button)"
     );
 
-    if let Some(mut prizes) = STORE.get().await?.ten_pull_cache.remove(&user_id) {
-        let prize = prizes.swap_remove(data[14] as usize - 1);
+    let index = data[14] as usize;
+    // Out of the if, the store stays locked for the whole block otherwise
+    let prizes = STORE.get().await?.ten_pull_cache.remove(&user_id);
+    if let Some(mut prizes) = prizes
+        && (1..=prizes.len()).contains(&index)
+    {
+        let prize = prizes.swap_remove(index - 1);
+        credit_prize(sender_id, &prize).await;
         let message_text = format!(
-            "亲爱的[{}](tg://user?id={})\\\n今天的老婆是 [{}]({})",
-            sender_name, sender_id, prize.name, prize.url,
+            "亲爱的[{}](tg://user?id={})\\\n今天的老婆是 {}",
+            sender_name,
+            sender_id,
+            prize.markdown_names(),
         );
         let input_message = InputMessage::new().markdown(message_text);
-        match prize.photo {
-            PrizePhoto::File {
-                name: filename,
-                content,
-            } => {
-                let len = content.len();
-                let mut cursor = std::io::Cursor::new(content);
-                let uploaded = client.upload_stream(&mut cursor, len, filename).await?;
-                query.answer().edit(input_message.photo(uploaded)).await?;
-            }
-            PrizePhoto::Url(photo_url) => {
-                query
-                    .answer()
-                    .edit(input_message.photo_url(photo_url))
-                    .await?;
-            }
-            PrizePhoto::TelegramPhoto(photo) => {
-                let photo = photo.clone().into();
-                query
-                    .answer()
-                    .edit(input_message.copy_media(&photo))
-                    .await?;
-            }
-        }
+        let input_message = with_photo(&client, input_message, prize.photo).await?;
+        query.answer().edit(input_message).await?;
     }
 
     // WIP
     return Ok(());
 }
+
+/// Count a prize the user was handed towards their collection.
+/// A failure here shouldn't keep the prize from being delivered, so it is only logged.
+async fn credit_prize(user_id: i64, prize: &Prize) {
+    let result: Result<()> = try {
+        let db = STORE.get().await?.database();
+        characters::credit(&db, user_id, &prize.characters).await?
+    };
+    if let Err(e) = result {
+        tracing::warn!(
+            user_id,
+            "Failed to credit {}: {:#}",
+            prize.display_name(),
+            e
+        );
+    }
+}
+
+/// Attach a prize photo to a message, uploading it if we have to
+async fn with_photo(
+    client: &Client,
+    input_message: InputMessage,
+    photo: PrizePhoto,
+) -> Result<InputMessage> {
+    Ok(match photo {
+        PrizePhoto::File {
+            name: filename,
+            content,
+            as_document,
+        } => {
+            let len = content.len();
+            let mut cursor = std::io::Cursor::new(content);
+            let uploaded = client.upload_stream(&mut cursor, len, filename).await?;
+            if as_document {
+                input_message.document(uploaded)
+            } else {
+                input_message.photo(uploaded)
+            }
+        }
+        PrizePhoto::Url(photo_url) => input_message.photo_url(photo_url),
+        PrizePhoto::TelegramPhoto(photo) => {
+            let photo = photo.into();
+            input_message.copy_media(&photo)
+        }
+    })
+}
+
+/// Turn a pull error into something we can show in the chat
+fn user_facing_error(e: &anyhow::Error) -> String {
+    if let Some(e) = e.downcast_ref::<DanbooruError>() {
+        return e.user_message();
+    }
+    if let Some(CharacterNotFound(name)) = e.downcast_ref::<CharacterNotFound>() {
+        return format!("频道里找不到 {name}，换个名字试试");
+    }
+    if let Some(failed) = e.downcast_ref::<PullFailed>() {
+        // The special prize is what the user was promised, say why it didn't come
+        if let Some(e) = failed
+            .special_error()
+            .and_then(|e| e.downcast_ref::<DanbooruError>())
+        {
+            return e.user_message();
+        }
+        return "老婆们都不在家，稍后再试吧".to_owned();
+    }
+    "出了点问题，老婆没能送达，稍后再试吧".to_owned()
+}
@@ -0,0 +1,253 @@
+// On-disk cache of downsized images, keyed by where they came from, so ten pulls don't
+// download the same posts again. Least recently used files go first when it is full.
+// File modification times double as last use, so a restart picks up where it left off.
+use crate::config::{IMAGE_CACHE_DIR, IMAGE_CACHE_MAX_BYTES};
+use anyhow::Result;
+use bytes::Bytes;
+use std::collections::HashMap;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{LazyLock, Mutex};
+use std::time::SystemTime;
+
+pub static IMAGE_CACHE: LazyLock<ImageCache> =
+    LazyLock::new(|| ImageCache::open(IMAGE_CACHE_DIR.into(), *IMAGE_CACHE_MAX_BYTES));
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ImageKey {
+    ChannelPost(i32),
+    /// Danbooru media asset id, shared by reposts of the same file
+    DanbooruAsset(u64),
+}
+
+impl ImageKey {
+    fn file_name(&self) -> String {
+        match self {
+            Self::ChannelPost(post_id) => format!("channel-{post_id}.jpg"),
+            Self::DanbooruAsset(asset_id) => format!("danbooru-{asset_id}.jpg"),
+        }
+    }
+}
+
+struct Entry {
+    size: u64,
+    last_used: SystemTime,
+}
+
+pub struct ImageCache {
+    dir: PathBuf,
+    max_bytes: u64,
+    /// key: file name
+    entries: Mutex<HashMap<String, Entry>>,
+    /// Numbers temp files, so concurrent writes of the same key don't share one
+    next_tmp: AtomicU64,
+}
+
+impl ImageCache {
+    fn open(dir: PathBuf, max_bytes: u64) -> Self {
+        let mut entries = HashMap::new();
+        if let Err(e) = fs::create_dir_all(&dir) {
+            tracing::warn!("Failed to create image cache {}: {}", dir.display(), e);
+        }
+        for entry in fs::read_dir(&dir).into_iter().flatten().flatten() {
+            let Ok(metadata) = entry.metadata() else {
+                continue;
+            };
+            let name = entry.file_name().to_string_lossy().into_owned();
+            if !metadata.is_file() || name.ends_with(".tmp") {
+                continue;
+            }
+            entries.insert(
+                name,
+                Entry {
+                    size: metadata.len(),
+                    last_used: metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH),
+                },
+            );
+        }
+        let total = entries.values().map(|entry| entry.size).sum::<u64>();
+        tracing::info!(
+            "Image cache has {} files, {} kB",
+            entries.len(),
+            total / 1024
+        );
+        Self {
+            dir,
+            max_bytes,
+            entries: Mutex::new(entries),
+            next_tmp: AtomicU64::new(0),
+        }
+    }
+
+    pub async fn get(&self, key: ImageKey) -> Option<Bytes> {
+        let name = key.file_name();
+        if !self.entries.lock().unwrap().contains_key(&name) {
+            return None;
+        }
+        let path = self.dir.join(&name);
+        match tokio::fs::read(&path).await {
+            Ok(bytes) => {
+                let now = SystemTime::now();
+                if let Some(entry) = self.entries.lock().unwrap().get_mut(&name) {
+                    entry.last_used = now;
+                }
+                // Remember the use across restarts, it's fine if this fails
+                let _ = tokio::task::spawn_blocking(move || {
+                    fs::File::open(&path).and_then(|file| file.set_modified(now))
+                })
+                .await;
+                Some(bytes.into())
+            }
+            Err(e) => {
+                tracing::warn!("Dropping unreadable cache file {}: {}", name, e);
+                self.entries.lock().unwrap().remove(&name);
+                None
+            }
+        }
+    }
+
+    pub async fn put(&self, key: ImageKey, bytes: &[u8]) -> Result<()> {
+        let name = key.file_name();
+        // Write then rename, so a crash never leaves half a file behind
+        let n = self.next_tmp.fetch_add(1, Ordering::Relaxed);
+        let tmp = self
+            .dir
+            .join(format!("{name}.{}-{n}.tmp", std::process::id()));
+        tokio::fs::write(&tmp, bytes).await?;
+        tokio::fs::rename(&tmp, self.dir.join(&name)).await?;
+        self.entries.lock().unwrap().insert(
+            name,
+            Entry {
+                size: bytes.len() as u64,
+                last_used: SystemTime::now(),
+            },
+        );
+        self.evict().await;
+        Ok(())
+    }
+
+    /// Forget an image whose source changed or is gone
+    pub async fn remove(&self, key: ImageKey) {
+        let name = key.file_name();
+        if self.entries.lock().unwrap().remove(&name).is_none() {
+            return;
+        }
+        if let Err(e) = tokio::fs::remove_file(self.dir.join(&name)).await
+            && e.kind() != std::io::ErrorKind::NotFound
+        {
+            tracing::warn!("Failed to remove {} from the image cache: {}", name, e);
+        }
+    }
+
+    /// Remove least recently used files until we are under the limit. The victims are picked
+    /// under the lock and deleted after it is released, so lookups don't wait on the disk.
+    async fn evict(&self) {
+        let (victims, total) = {
+            let mut entries = self.entries.lock().unwrap();
+            let mut total = entries.values().map(|entry| entry.size).sum::<u64>();
+            if total <= self.max_bytes {
+                return;
+            }
+            let mut by_age = entries
+                .iter()
+                .map(|(name, entry)| (entry.last_used, name.clone()))
+                .collect::<Vec<_>>();
+            by_age.sort();
+            let mut victims = vec![];
+            for (_, name) in by_age {
+                if total <= self.max_bytes {
+                    break;
+                }
+                if let Some(entry) = entries.remove(&name) {
+                    total -= entry.size;
+                }
+                victims.push(name);
+            }
+            (victims, total)
+        };
+        for name in victims {
+            if let Err(e) = tokio::fs::remove_file(self.dir.join(&name)).await {
+                tracing::warn!("Failed to evict {}: {}", name, e);
+            }
+        }
+        tracing::debug!("Image cache evicted down to {} kB", total / 1024);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("image-cache-{name}-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn names(dir: &PathBuf) -> Vec<String> {
+        let mut names = fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .collect::<Vec<_>>();
+        names.sort();
+        names
+    }
+
+    #[tokio::test]
+    async fn evicts_least_recently_used_first() {
+        let dir = temp_dir("evict");
+        let cache = ImageCache::open(dir.clone(), 30);
+        for post_id in 1..=3 {
+            cache
+                .put(ImageKey::ChannelPost(post_id), &[0; 10])
+                .await
+                .unwrap();
+        }
+        // 1 is now more recent than 2 and 3
+        assert!(cache.get(ImageKey::ChannelPost(1)).await.is_some());
+
+        cache.put(ImageKey::ChannelPost(4), &[0; 10]).await.unwrap();
+        assert_eq!(
+            names(&dir),
+            ["channel-1.jpg", "channel-3.jpg", "channel-4.jpg"]
+        );
+        assert!(cache.get(ImageKey::ChannelPost(2)).await.is_none());
+
+        // Too large for one slot, takes the two oldest with it
+        cache
+            .put(ImageKey::DanbooruAsset(5), &[0; 20])
+            .await
+            .unwrap();
+        assert_eq!(names(&dir), ["channel-4.jpg", "danbooru-5.jpg"]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn reopening_keeps_entries_and_their_order() {
+        let dir = temp_dir("reopen");
+        let cache = ImageCache::open(dir.clone(), 30);
+        for post_id in 1..=3 {
+            cache
+                .put(ImageKey::ChannelPost(post_id), &[post_id as u8; 10])
+                .await
+                .unwrap();
+        }
+        assert!(cache.get(ImageKey::ChannelPost(1)).await.is_some());
+        // A write that never got renamed
+        fs::write(dir.join("channel-9.jpg.1-0.tmp"), [0; 10]).unwrap();
+        drop(cache);
+
+        let cache = ImageCache::open(dir.clone(), 30);
+        assert_eq!(cache.entries.lock().unwrap().len(), 3);
+        assert_eq!(
+            cache.get(ImageKey::ChannelPost(3)).await.unwrap(),
+            Bytes::from(vec![3; 10])
+        );
+        // 2 is the oldest now that 1 and 3 were read
+        cache.put(ImageKey::ChannelPost(4), &[4; 10]).await.unwrap();
+        assert!(!dir.join("channel-2.jpg").exists());
+        assert!(dir.join("channel-1.jpg").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,144 @@
+// Same-sized cells in a fixed number of columns. Cells share one aspect ratio, the items
+// come out with `forced_aspect_ratio` so the renderer center-crops into them.
+use super::{Layout, LayoutConfig, LayoutItem, LayoutResult, WidowLayoutStyle};
+use anyhow::Result;
+
+pub struct Grid {
+    pub columns: usize,
+}
+
+impl Layout for Grid {
+    fn compute(&self, input: &[f64], config: &LayoutConfig) -> Result<LayoutResult> {
+        let padding = config.container_padding;
+        let spacing = config.box_spacing;
+        let columns = self.columns.max(1);
+
+        let mut count = input.len().min(columns.saturating_mul(config.max_num_rows));
+        let mut widow_count = count % columns;
+        if !config.show_widows {
+            count -= widow_count;
+            widow_count = 0;
+        }
+        let rows = count.div_ceil(columns);
+
+        // The median keeps one odd panorama from deciding the shape of every cell
+        let cell_ratio = config
+            .force_aspect_ratio
+            .unwrap_or_else(|| median(&input[..count]));
+        let content_width = config.container_width - padding.left - padding.right;
+        let cell_width =
+            (content_width - spacing.horizontal * (columns - 1) as f64) / columns as f64;
+        let cell_height = cell_width / cell_ratio;
+
+        let boxes = (0..count)
+            .map(|i| {
+                let (row, column) = (i / columns, i % columns);
+                let in_row = (count - row * columns).min(columns);
+                let offset = if config.widow_layout_style == WidowLayoutStyle::Center {
+                    (columns - in_row) as f64 * (cell_width + spacing.horizontal) / 2.
+                } else {
+                    0.
+                };
+                LayoutItem {
+                    aspect_ratio: cell_ratio,
+                    top: padding.top + row as f64 * (cell_height + spacing.vertical),
+                    left: padding.left + offset + column as f64 * (cell_width + spacing.horizontal),
+                    width: cell_width,
+                    height: cell_height,
+                    forced_aspect_ratio: true,
+                }
+            })
+            .collect();
+
+        let container_height = padding.top + rows as f64 * (cell_height + spacing.vertical)
+            - spacing.vertical
+            + padding.bottom;
+        Ok(LayoutResult {
+            container_height,
+            widow_count,
+            boxes,
+        })
+    }
+}
+
+fn median(ratios: &[f64]) -> f64 {
+    let mut sorted = ratios.to_vec();
+    sorted.sort_by(f64::total_cmp);
+    sorted.get(sorted.len() / 2).copied().unwrap_or(1.)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> LayoutConfig {
+        LayoutConfig {
+            container_width: 1000.,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn cells_share_the_median_ratio() {
+        let result = Grid { columns: 4 }
+            .compute(&[0.5, 1., 4., 1.5, 0.75], &config())
+            .unwrap();
+        // (980 - 3 * 10) / 4 wide, the median ratio is 1
+        for item in &result.boxes {
+            assert_eq!(item.width, 237.5);
+            assert_eq!(item.height, 237.5);
+            assert!(item.forced_aspect_ratio);
+        }
+        assert_eq!(result.container_height, 10. + 2. * 237.5 + 10. + 10.);
+    }
+
+    #[test]
+    fn widows_fill_the_last_row_from_the_left() {
+        let result = Grid { columns: 4 }.compute(&[1.; 10], &config()).unwrap();
+        assert_eq!(result.boxes.len(), 10);
+        assert_eq!(result.widow_count, 2);
+        let widows = &result.boxes[8..];
+        assert_eq!(widows[0].left, 10.);
+        assert_eq!(widows[1].left, 10. + 237.5 + 10.);
+        assert_eq!(widows[0].top, 10. + 2. * (237.5 + 10.));
+    }
+
+    #[test]
+    fn centered_widows() {
+        let config = LayoutConfig {
+            widow_layout_style: WidowLayoutStyle::Center,
+            ..config()
+        };
+        let result = Grid { columns: 4 }.compute(&[1.; 10], &config).unwrap();
+        // Two cells short, so shifted by one cell
+        assert_eq!(result.boxes[8].left, 10. + 237.5 + 10.);
+        assert_eq!(result.boxes[9].left, 10. + 2. * (237.5 + 10.));
+        // Full rows stay put
+        assert_eq!(result.boxes[4].left, 10.);
+    }
+
+    #[test]
+    fn hidden_widows_are_dropped() {
+        let config = LayoutConfig {
+            show_widows: false,
+            ..config()
+        };
+        let result = Grid { columns: 4 }.compute(&[1.; 10], &config).unwrap();
+        assert_eq!(result.boxes.len(), 8);
+        assert_eq!(result.widow_count, 0);
+        assert_eq!(result.container_height, 10. + 2. * 237.5 + 10. + 10.);
+    }
+
+    #[test]
+    fn max_num_rows_truncates() {
+        let config = LayoutConfig {
+            max_num_rows: 2,
+            ..config()
+        };
+        let result = Grid { columns: 3 }.compute(&[1.; 10], &config).unwrap();
+        assert_eq!(result.boxes.len(), 6);
+        // Both rows are full, nothing left over to be a widow
+        assert_eq!(result.widow_count, 0);
+        assert!(result.boxes.iter().all(|item| item.top < 10. + 2. * 320.));
+    }
+}
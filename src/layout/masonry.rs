@@ -0,0 +1,126 @@
+// Equal-width columns, each box goes under the shortest column so far. Nothing gets
+// cropped, the columns just end at different heights. There are no rows, so
+// `max_num_rows` and the widow settings don't apply.
+use super::{Layout, LayoutConfig, LayoutItem, LayoutResult};
+use anyhow::Result;
+
+pub struct Masonry {
+    pub columns: usize,
+}
+
+impl Layout for Masonry {
+    fn compute(&self, input: &[f64], config: &LayoutConfig) -> Result<LayoutResult> {
+        let padding = config.container_padding;
+        let spacing = config.box_spacing;
+        // Empty columns would only waste width
+        let columns = self.columns.max(1).min(input.len().max(1));
+
+        let content_width = config.container_width - padding.left - padding.right;
+        let column_width =
+            (content_width - spacing.horizontal * (columns - 1) as f64) / columns as f64;
+
+        // Where the next box in each column goes
+        let mut bottoms = vec![padding.top; columns];
+        let mut boxes = Vec::with_capacity(input.len());
+        for &ar in input {
+            let aspect_ratio = config.force_aspect_ratio.unwrap_or(ar);
+            // The first of equally short columns, so ties fill left to right
+            let (column, top) = bottoms
+                .iter()
+                .copied()
+                .enumerate()
+                .min_by(|a, b| a.1.total_cmp(&b.1))
+                .unwrap();
+            let height = column_width / aspect_ratio;
+            boxes.push(LayoutItem {
+                aspect_ratio,
+                top,
+                left: padding.left + column as f64 * (column_width + spacing.horizontal),
+                width: column_width,
+                height,
+                forced_aspect_ratio: config.force_aspect_ratio.is_some(),
+            });
+            bottoms[column] = top + height + spacing.vertical;
+        }
+
+        let tallest = bottoms.into_iter().fold(padding.top, f64::max);
+        Ok(LayoutResult {
+            container_height: tallest - spacing.vertical + padding.bottom,
+            widow_count: 0,
+            boxes,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> LayoutConfig {
+        LayoutConfig {
+            container_width: 1000.,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn boxes_go_under_the_shortest_column() {
+        let result = Masonry { columns: 3 }
+            .compute(&[1., 0.5, 2., 1.], &config())
+            .unwrap();
+        // (980 - 2 * 10) / 3 wide, columns end at 340, 660 and 180
+        let boxes = &result.boxes;
+        assert_eq!(boxes[2].height, 160.);
+        assert_eq!(boxes[3].left, 10. + 2. * (320. + 10.));
+        assert_eq!(boxes[3].top, 10. + 160. + 10.);
+        assert_eq!(result.container_height, 10. + 640. + 10.);
+        assert_eq!(result.widow_count, 0);
+    }
+
+    #[test]
+    fn column_width_follows_the_container() {
+        let result = Masonry { columns: 2 }.compute(&[1.; 4], &config()).unwrap();
+        for item in &result.boxes {
+            assert_eq!(item.width, 485.);
+            assert!(!item.forced_aspect_ratio);
+        }
+        let wide = LayoutConfig {
+            container_width: 2000.,
+            ..config()
+        };
+        let result = Masonry { columns: 2 }.compute(&[1.; 4], &wide).unwrap();
+        assert_eq!(result.boxes[1].left, 10. + 985. + 10.);
+        assert_eq!(result.boxes[0].width, 985.);
+    }
+
+    #[test]
+    fn fewer_boxes_than_columns_use_fewer_columns() {
+        let result = Masonry { columns: 4 }
+            .compute(&[1., 1.], &config())
+            .unwrap();
+        let lefts = result
+            .boxes
+            .iter()
+            .map(|item| item.left)
+            .collect::<Vec<_>>();
+        assert_eq!(lefts, [10., 10. + 485. + 10.]);
+    }
+
+    #[test]
+    fn boxes_keep_the_input_order() {
+        let input = [1.5, 0.75, 1., 2., 0.5, 1.25];
+        let result = Masonry { columns: 3 }.compute(&input, &config()).unwrap();
+        let ratios = result
+            .boxes
+            .iter()
+            .map(|item| item.aspect_ratio)
+            .collect::<Vec<_>>();
+        assert_eq!(ratios, input);
+        // Equally short columns fill left to right
+        let first_row = result.boxes[..3]
+            .iter()
+            .map(|item| (item.left, item.top))
+            .collect::<Vec<_>>();
+        assert_eq!(first_row, [(10., 10.), (340., 10.), (670., 10.)]);
+    }
+}
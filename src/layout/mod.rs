@@ -1,9 +1,20 @@
 // based on https://github.com/flickr/justified-layout
+// Other strategies live next to it, all behind the `Layout` trait.
 #![allow(dead_code)]
+mod grid;
+mod masonry;
+mod optimal;
 mod row;
-use anyhow::Result;
+use anyhow::{Result, anyhow};
+pub use grid::Grid;
+pub use masonry::Masonry;
+pub use optimal::Optimal;
 use row::Row;
 pub use row::{LayoutItem, WidowLayoutStyle};
+use std::str::FromStr;
+
+/// Shorter rows tried before `compute` gives up and shrinks the whole layout
+const MAX_FIT_ATTEMPTS: usize = 8;
 
 #[derive(Debug, Clone, Copy)]
 pub struct Padding {
@@ -39,8 +50,69 @@ impl Default for Spacing {
     }
 }
 
+/// Places boxes with the given aspect ratios inside `LayoutConfig::container_width`
+pub trait Layout {
+    fn compute(&self, input: &[f64], config: &LayoutConfig) -> Result<LayoutResult>;
+}
+
+/// The flickr port: rows fill up greedily until they are close to the target height
+pub struct Justified;
+
+impl Layout for Justified {
+    fn compute(&self, input: &[f64], config: &LayoutConfig) -> Result<LayoutResult> {
+        compute_justified(input, config)
+    }
+}
+
+/// Which `Layout` `compute` uses
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LayoutAlgorithm {
+    Justified,
+    /// Justified, with row breaks chosen for the whole set at once
+    Optimal,
+    /// Same-sized cells, pictures are center-cropped to fill them
+    Grid {
+        columns: usize,
+    },
+    /// Equal-width columns, nothing cropped
+    Masonry {
+        columns: usize,
+    },
+}
+
+impl FromStr for LayoutAlgorithm {
+    type Err = anyhow::Error;
+
+    /// `justified`, `optimal`, `grid:4` or `masonry:3`, the number is the column count
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, columns) = match s.trim().split_once(':') {
+            Some((name, columns)) => (name, Some(columns.trim().parse::<usize>()?)),
+            None => (s.trim(), None),
+        };
+        match (name, columns) {
+            ("justified", None) => Ok(Self::Justified),
+            ("optimal", None) => Ok(Self::Optimal),
+            ("grid", Some(columns @ 1..)) => Ok(Self::Grid { columns }),
+            ("masonry", Some(columns @ 1..)) => Ok(Self::Masonry { columns }),
+            _ => Err(anyhow!("Unknown layout algorithm '{s}'")),
+        }
+    }
+}
+
+impl Layout for LayoutAlgorithm {
+    fn compute(&self, input: &[f64], config: &LayoutConfig) -> Result<LayoutResult> {
+        match *self {
+            Self::Justified => Justified.compute(input, config),
+            Self::Optimal => Optimal.compute(input, config),
+            Self::Grid { columns } => Grid { columns }.compute(input, config),
+            Self::Masonry { columns } => Masonry { columns }.compute(input, config),
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct LayoutConfig {
+    pub algorithm: LayoutAlgorithm,
     pub container_width: f64,
     pub container_padding: Padding,
     pub box_spacing: Spacing,
@@ -53,23 +125,43 @@ pub struct LayoutConfig {
     pub show_widows: bool,
     pub full_width_breakout_row_cadence: usize,
     pub widow_layout_style: WidowLayoutStyle,
+    /// Rows get shorter, so more fit side by side, until the container is at most this tall
+    pub max_container_height: Option<f64>,
+    /// Same as `max_container_height`, as width / height of the container
+    pub min_container_aspect_ratio: Option<f64>,
+}
+
+impl LayoutConfig {
+    /// The tighter of `max_container_height` and `min_container_aspect_ratio`
+    pub fn height_limit(&self) -> Option<f64> {
+        let from_ratio = self
+            .min_container_aspect_ratio
+            .map(|ratio| self.container_width / ratio);
+        match (self.max_container_height, from_ratio) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        }
+    }
 }
 
 impl Default for LayoutConfig {
     fn default() -> Self {
         Self {
+            algorithm: LayoutAlgorithm::Justified,
             container_width: 1060.0,
             container_padding: Padding::default(),
             box_spacing: Spacing::default(),
             target_row_height: vec![320.0],
             target_row_height_tolerance: 0.25,
             edge_case_min_row_height_factor: 0.5,
-            edge_case_max_row_height_factor: 2.,
+            edge_case_max_row_height_factor: 2.5,
             max_num_rows: usize::MAX,
             force_aspect_ratio: None,
             show_widows: true,
             full_width_breakout_row_cadence: 0,
             widow_layout_style: WidowLayoutStyle::Left,
+            max_container_height: None,
+            min_container_aspect_ratio: None,
         }
     }
 }
@@ -121,6 +213,74 @@ impl LayoutState {
 
 // input contains aspect ratios
 pub fn compute(input: &[f64], config: &LayoutConfig) -> Result<LayoutResult> {
+    if let Some(i) = input.iter().position(|ar| !(ar.is_finite() && *ar > 0.)) {
+        anyhow::bail!("Item {} has an invalid aspect ratio", i);
+    }
+    let mut result = config.algorithm.compute(input, config)?;
+    let Some(max_height) = config.height_limit() else {
+        return Ok(result);
+    };
+    if result.container_height <= max_height {
+        return Ok(result);
+    }
+
+    // Shorter rows take more items each and more columns are shorter, either way it's less tall
+    let vertical_padding = config.container_padding.top + config.container_padding.bottom;
+    let mut config = config.clone();
+    for _ in 0..MAX_FIT_ATTEMPTS {
+        let factor = ((max_height - vertical_padding)
+            / (result.container_height - vertical_padding))
+            .clamp(0.5, 0.95);
+        tighten(&mut config, factor);
+        result = config.algorithm.compute(input, &config)?;
+        if result.container_height <= max_height {
+            return Ok(result);
+        }
+    }
+
+    tracing::debug!(
+        height = result.container_height,
+        max_height,
+        "Layout still too tall, shrinking it"
+    );
+    Ok(shrink_to_height(result, &config, max_height))
+}
+
+fn tighten(config: &mut LayoutConfig, factor: f64) {
+    match &mut config.algorithm {
+        LayoutAlgorithm::Justified | LayoutAlgorithm::Optimal => {
+            for height in &mut config.target_row_height {
+                *height *= factor;
+            }
+        }
+        LayoutAlgorithm::Grid { columns } | LayoutAlgorithm::Masonry { columns } => {
+            *columns = (*columns).max(1) + 1;
+        }
+    }
+}
+
+/// Scale all boxes down to fit `max_height`, centered horizontally
+fn shrink_to_height(
+    mut result: LayoutResult,
+    config: &LayoutConfig,
+    max_height: f64,
+) -> LayoutResult {
+    let padding = config.container_padding;
+    let content_height = result.container_height - padding.top - padding.bottom;
+    let scale = ((max_height - padding.top - padding.bottom) / content_height).clamp(0., 1.);
+    let content_width = config.container_width - padding.left - padding.right;
+    let offset = content_width * (1. - scale) / 2.;
+    for item in &mut result.boxes {
+        item.top = padding.top + (item.top - padding.top) * scale;
+        item.left = padding.left + offset + (item.left - padding.left) * scale;
+        item.width *= scale;
+        item.height *= scale;
+    }
+    result.container_height = max_height;
+    result
+}
+
+fn compute_justified(input: &[f64], config: &LayoutConfig) -> Result<LayoutResult> {
     let mut state = LayoutState {
         container_height: config.container_padding.top,
         rows: Vec::new(),
@@ -139,12 +299,8 @@ pub fn compute(input: &[f64], config: &LayoutConfig) -> Result<LayoutResult> {
 
     let mut current_row: Option<Row> = None;
 
-    for (i, item_data) in item_layout_data.into_iter().enumerate() {
-        if item_data.aspect_ratio.is_nan() {
-            anyhow::bail!("Item {} has an invalid aspect ratio", i);
-        }
-
-        let mut row = current_row.unwrap_or(state.create_new_row(config));
+    for item_data in item_layout_data {
+        let mut row = current_row.unwrap_or_else(|| state.create_new_row(config));
 
         let item_added = row.add_item(item_data);
 
@@ -205,3 +361,578 @@ pub fn compute(input: &[f64], config: &LayoutConfig) -> Result<LayoutResult> {
         boxes: laid_out_items,
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_inside(result: &LayoutResult, config: &LayoutConfig, count: usize) {
+        assert_eq!(result.boxes.len(), count);
+        let right = config.container_width - config.container_padding.right;
+        for item in &result.boxes {
+            assert!(
+                item.left >= config.container_padding.left - 1e-6,
+                "{item:?}"
+            );
+            // Justified rows spread rounding errors, up to a px
+            assert!(item.left + item.width <= right + 1., "{item:?}");
+            assert!(
+                item.top + item.height <= result.container_height + 1e-6,
+                "{item:?}"
+            );
+        }
+    }
+
+    /// Rows that fill the content width, so the layout wasn't shrunk as a whole
+    fn assert_full_width(result: &LayoutResult, config: &LayoutConfig) {
+        let right = config.container_width - config.container_padding.right;
+        let widest = result
+            .boxes
+            .iter()
+            .map(|item| item.left + item.width)
+            .fold(0., f64::max);
+        assert!((widest - right).abs() <= 1., "widest row ends at {widest}");
+    }
+
+    /// `boxes`: [top, left, width, height]
+    fn assert_layout(
+        result: &LayoutResult,
+        container_height: f64,
+        widow_count: usize,
+        boxes: &[[f64; 4]],
+    ) {
+        let close = |a: f64, b: f64| (a - b).abs() < 1e-6;
+        assert!(
+            close(result.container_height, container_height),
+            "container height {} != {container_height}",
+            result.container_height
+        );
+        assert_eq!(result.widow_count, widow_count);
+        assert_eq!(result.boxes.len(), boxes.len());
+        for (i, (item, expected)) in result.boxes.iter().zip(boxes).enumerate() {
+            let actual = [item.top, item.left, item.width, item.height];
+            assert!(
+                actual.iter().zip(expected).all(|(&a, &b)| close(a, b)),
+                "box {i}: {actual:?} != {expected:?}"
+            );
+        }
+    }
+
+    // Test vectors of flickr/justified-layout, on its default config
+
+    /// The README example, default config
+    #[test]
+    fn upstream_readme_example() {
+        let config = LayoutConfig::default();
+        let result = compute(
+            &[0.5, 1.5, 1.0, 1.8, 0.4, 0.7, 0.9, 1.1, 1.7, 2.0, 2.1],
+            &config,
+        )
+        .unwrap();
+        assert_layout(
+            &result,
+            1268.619325801832,
+            0,
+            &[
+                [10.0, 10.0, 170.0, 340.0],
+                [10.0, 190.0, 510.0, 340.0],
+                [10.0, 710.0, 340.0, 340.0],
+                [360.0, 10.0, 633.103448275862, 351.7241379310344],
+                [
+                    360.0,
+                    653.103448275862,
+                    140.68965517241378,
+                    351.7241379310344,
+                ],
+                [
+                    360.0,
+                    803.7931034482758,
+                    246.20689655172407,
+                    351.7241379310344,
+                ],
+                [
+                    721.7241379310344,
+                    10.0,
+                    248.10810810810813,
+                    275.6756756756757,
+                ],
+                [
+                    721.7241379310344,
+                    268.1081081081081,
+                    303.2432432432433,
+                    275.6756756756757,
+                ],
+                [
+                    721.7241379310344,
+                    581.3513513513515,
+                    468.64864864864865,
+                    275.6756756756757,
+                ],
+                [
+                    1007.39981360671,
+                    10.0,
+                    502.43902439024396,
+                    251.21951219512198,
+                ],
+                [
+                    1007.39981360671,
+                    522.439024390244,
+                    527.5609756097562,
+                    251.21951219512198,
+                ],
+            ],
+        );
+    }
+
+    /// A row of three, the fourth is a widow at the previous row's height
+    #[test]
+    fn upstream_four_squares() {
+        let config = LayoutConfig::default();
+        let result = compute(&[1.0, 1.0, 1.0, 1.0], &config).unwrap();
+        assert_layout(
+            &result,
+            710.0,
+            1,
+            &[
+                [10.0, 10.0, 340.0, 340.0],
+                [10.0, 360.0, 340.0, 340.0],
+                [10.0, 710.0, 340.0, 340.0],
+                [360.0, 10.0, 340.0, 340.0],
+            ],
+        );
+    }
+
+    /// The third item would overfill the row, so the row closes and it starts the next one
+    #[test]
+    fn upstream_rejected_item_starts_the_next_row() {
+        let config = LayoutConfig::default();
+        let result = compute(&[1.5, 0.8, 3.0], &config).unwrap();
+        assert_layout(
+            &result,
+            824.4927536231885,
+            0,
+            &[
+                [10.0, 10.0, 671.7391304347826, 447.82608695652175],
+                [
+                    10.0,
+                    691.7391304347826,
+                    358.26086956521743,
+                    447.82608695652175,
+                ],
+                [467.82608695652175, 10.0, 1040.0, 346.6666666666667],
+            ],
+        );
+    }
+
+    /// Too wide for any row, a panorama takes one by itself
+    #[test]
+    fn upstream_panorama_gets_its_own_row() {
+        let config = LayoutConfig::default();
+        let result = compute(&[5.0, 1.0, 1.0], &config).unwrap();
+        assert_layout(
+            &result,
+            446.0,
+            2,
+            &[
+                [10.0, 10.0, 1040.0, 208.0],
+                [228.0, 10.0, 208.0, 208.0],
+                [228.0, 228.0, 208.0, 208.0],
+            ],
+        );
+    }
+
+    /// A lone widow gets the target height
+    #[test]
+    fn upstream_single_item() {
+        let config = LayoutConfig::default();
+        let result = compute(&[1.0], &config).unwrap();
+        assert_layout(&result, 340.0, 1, &[[10.0, 10.0, 320.0, 320.0]]);
+    }
+
+    #[test]
+    fn upstream_empty() {
+        let config = LayoutConfig::default();
+        let result = compute(&[], &config).unwrap();
+        assert_layout(&result, 10.0, 0, &[]);
+    }
+
+    #[test]
+    fn upstream_forced_aspect_ratio() {
+        let config = LayoutConfig {
+            force_aspect_ratio: Some(1.),
+            ..Default::default()
+        };
+        let result = compute(&[0.5, 2.0, 1.0, 3.0], &config).unwrap();
+        assert_layout(
+            &result,
+            710.0,
+            1,
+            &[
+                [10.0, 10.0, 340.0, 340.0],
+                [10.0, 360.0, 340.0, 340.0],
+                [10.0, 710.0, 340.0, 340.0],
+                [360.0, 10.0, 340.0, 340.0],
+            ],
+        );
+        assert!(result.boxes.iter().all(|item| item.forced_aspect_ratio));
+    }
+
+    #[test]
+    fn upstream_max_num_rows() {
+        let config = LayoutConfig {
+            max_num_rows: 1,
+            ..Default::default()
+        };
+        let result = compute(&[1.0, 1.0, 1.0, 1.0, 1.0, 1.0, 1.0], &config).unwrap();
+        assert_layout(
+            &result,
+            360.0,
+            0,
+            &[
+                [10.0, 10.0, 340.0, 340.0],
+                [10.0, 360.0, 340.0, 340.0],
+                [10.0, 710.0, 340.0, 340.0],
+            ],
+        );
+    }
+
+    /// Every second row is a breakout row, a landscape item there takes the full width.
+    /// The last one is clamped to the edge case maximum height.
+    #[test]
+    fn upstream_breakout_row_cadence() {
+        let config = LayoutConfig {
+            full_width_breakout_row_cadence: 2,
+            ..Default::default()
+        };
+        let result = compute(&[1.0, 1.0, 1.0, 1.5, 1.0, 1.0, 1.0, 1.0], &config).unwrap();
+        assert_layout(
+            &result,
+            2223.3333333333335,
+            0,
+            &[
+                [10.0, 10.0, 340.0, 340.0],
+                [10.0, 360.0, 340.0, 340.0],
+                [10.0, 710.0, 340.0, 340.0],
+                [360.0, 10.0, 1040.0, 693.3333333333334],
+                [1063.3333333333335, 10.0, 340.0, 340.0],
+                [1063.3333333333335, 360.0, 340.0, 340.0],
+                [1063.3333333333335, 710.0, 340.0, 340.0],
+                [1413.3333333333335, 10.0, 1040.0, 800.0],
+            ],
+        );
+    }
+
+    /// A portrait item doesn't break out, the row fills up as usual
+    #[test]
+    fn upstream_breakout_row_needs_a_landscape_item() {
+        let config = LayoutConfig {
+            full_width_breakout_row_cadence: 2,
+            ..Default::default()
+        };
+        let result = compute(&[1.0, 1.0, 1.0, 0.75, 1.0, 1.0], &config).unwrap();
+        assert_layout(
+            &result,
+            740.909090909091,
+            0,
+            &[
+                [10.0, 10.0, 340.0, 340.0],
+                [10.0, 360.0, 340.0, 340.0],
+                [10.0, 710.0, 340.0, 340.0],
+                [360.0, 10.0, 278.1818181818182, 370.90909090909093],
+                [
+                    360.0,
+                    298.1818181818182,
+                    370.90909090909093,
+                    370.90909090909093,
+                ],
+                [
+                    360.0,
+                    679.0909090909091,
+                    370.90909090909093,
+                    370.90909090909093,
+                ],
+            ],
+        );
+    }
+
+    /// A widow after a breakout row gets the target height, not the breakout's
+    #[test]
+    fn upstream_widow_after_breakout_row() {
+        let config = LayoutConfig {
+            full_width_breakout_row_cadence: 2,
+            ..Default::default()
+        };
+        let result = compute(&[1.0, 1.0, 1.0, 2.0, 1.0], &config).unwrap();
+        assert_layout(
+            &result,
+            1220.0,
+            1,
+            &[
+                [10.0, 10.0, 340.0, 340.0],
+                [10.0, 360.0, 340.0, 340.0],
+                [10.0, 710.0, 340.0, 340.0],
+                [360.0, 10.0, 1040.0, 520.0],
+                [890.0, 10.0, 320.0, 320.0],
+            ],
+        );
+    }
+
+    #[test]
+    fn upstream_widows_left() {
+        let config = LayoutConfig::default();
+        let result = compute(&[1.0, 1.0, 1.0, 1.0, 1.0], &config).unwrap();
+        assert_layout(
+            &result,
+            710.0,
+            2,
+            &[
+                [10.0, 10.0, 340.0, 340.0],
+                [10.0, 360.0, 340.0, 340.0],
+                [10.0, 710.0, 340.0, 340.0],
+                [360.0, 10.0, 340.0, 340.0],
+                [360.0, 360.0, 340.0, 340.0],
+            ],
+        );
+    }
+
+    #[test]
+    fn upstream_widows_center() {
+        let config = LayoutConfig {
+            widow_layout_style: WidowLayoutStyle::Center,
+            ..Default::default()
+        };
+        let result = compute(&[1.0, 1.0, 1.0, 1.0, 1.0], &config).unwrap();
+        assert_layout(
+            &result,
+            710.0,
+            2,
+            &[
+                [10.0, 10.0, 340.0, 340.0],
+                [10.0, 360.0, 340.0, 340.0],
+                [10.0, 710.0, 340.0, 340.0],
+                [360.0, 185.0, 340.0, 340.0],
+                [360.0, 535.0, 340.0, 340.0],
+            ],
+        );
+    }
+
+    /// Justified widows are stretched to the full width at the previous row's height
+    #[test]
+    fn upstream_widows_justify() {
+        let config = LayoutConfig {
+            widow_layout_style: WidowLayoutStyle::Justify,
+            ..Default::default()
+        };
+        let result = compute(&[1.0, 1.0, 1.0, 1.0, 1.0], &config).unwrap();
+        assert_layout(
+            &result,
+            710.0,
+            2,
+            &[
+                [10.0, 10.0, 340.0, 340.0],
+                [10.0, 360.0, 340.0, 340.0],
+                [10.0, 710.0, 340.0, 340.0],
+                [360.0, 10.0, 515.0, 340.0],
+                [360.0, 535.0, 515.0, 340.0],
+            ],
+        );
+    }
+
+    #[test]
+    fn upstream_hidden_widows() {
+        let config = LayoutConfig {
+            show_widows: false,
+            ..Default::default()
+        };
+        let result = compute(&[1.0, 1.0, 1.0, 1.0, 1.0], &config).unwrap();
+        assert_layout(
+            &result,
+            360.0,
+            0,
+            &[
+                [10.0, 10.0, 340.0, 340.0],
+                [10.0, 360.0, 340.0, 340.0],
+                [10.0, 710.0, 340.0, 340.0],
+            ],
+        );
+    }
+
+    #[test]
+    fn force_complete_fits_to_width() {
+        let config = LayoutConfig::default();
+        let state = LayoutState {
+            container_height: config.container_padding.top,
+            rows: Vec::new(),
+        };
+        let mut row = state.create_new_row(&config);
+        for _ in 0..2 {
+            row.items.push(LayoutItem::default());
+        }
+        row.force_complete(true, Some(100.));
+        // (1040 - 10) / 2, the row height is ignored
+        assert_eq!(row.height, 515.);
+        let boxes = row.get_items();
+        assert_eq!((boxes[0].left, boxes[0].width), (10., 515.));
+        assert_eq!((boxes[1].left, boxes[1].width), (535., 515.));
+    }
+
+    #[test]
+    fn force_complete_leaves_empty_rows_alone() {
+        let config = LayoutConfig::default();
+        let state = LayoutState {
+            container_height: config.container_padding.top,
+            rows: Vec::new(),
+        };
+        for fit_to_width in [true, false] {
+            let mut row = state.create_new_row(&config);
+            row.force_complete(fit_to_width, None);
+            assert!(!row.is_layout_complete());
+        }
+    }
+
+    #[test]
+    fn parses_algorithms() {
+        let parse = |s: &str| s.parse::<LayoutAlgorithm>().ok();
+        assert_eq!(parse("justified"), Some(LayoutAlgorithm::Justified));
+        assert_eq!(parse(" optimal "), Some(LayoutAlgorithm::Optimal));
+        assert_eq!(parse("grid:4"), Some(LayoutAlgorithm::Grid { columns: 4 }));
+        assert_eq!(
+            parse("masonry: 3"),
+            Some(LayoutAlgorithm::Masonry { columns: 3 })
+        );
+        assert_eq!(parse("grid"), None);
+        assert_eq!(parse("grid:0"), None);
+        assert_eq!(parse("justified:2"), None);
+        assert_eq!(parse("spiral"), None);
+    }
+
+    #[test]
+    fn every_strategy_places_each_input_inside_the_width() {
+        let inputs: [&[f64]; 5] = [
+            &[],
+            &[1.],
+            &[0.5, 1.5, 1., 1.8, 0.4, 0.7, 0.9, 1.1, 1.7, 2.],
+            &[0.5, 0.6, 0.7, 0.55, 0.65, 0.75, 0.5, 0.6, 0.7, 0.8],
+            &[3., 2.5, 4., 1.9, 2.2, 3.5, 2.8],
+        ];
+        let algorithms = [
+            LayoutAlgorithm::Justified,
+            LayoutAlgorithm::Optimal,
+            LayoutAlgorithm::Grid { columns: 3 },
+            LayoutAlgorithm::Masonry { columns: 3 },
+        ];
+        for algorithm in algorithms {
+            let config = LayoutConfig {
+                algorithm,
+                ..Default::default()
+            };
+            for input in inputs {
+                let result = compute(input, &config).unwrap();
+                assert_inside(&result, &config, input.len());
+            }
+        }
+    }
+
+    #[test]
+    fn height_limit_is_the_tighter_one() {
+        let config = LayoutConfig {
+            container_width: 1000.,
+            max_container_height: Some(1500.),
+            min_container_aspect_ratio: Some(0.5),
+            ..Default::default()
+        };
+        assert_eq!(config.height_limit(), Some(1500.));
+        let config = LayoutConfig {
+            max_container_height: Some(2500.),
+            ..config
+        };
+        assert_eq!(config.height_limit(), Some(2000.));
+        assert_eq!(LayoutConfig::default().height_limit(), None);
+    }
+
+    #[test]
+    fn all_portrait_fits_by_tightening_rows() {
+        let input = [0.5, 0.6, 0.7, 0.55, 0.65, 0.75, 0.5, 0.6, 0.7, 0.8];
+        let config = LayoutConfig {
+            max_container_height: Some(900.),
+            ..Default::default()
+        };
+        let untightened = compute(&input, &LayoutConfig::default()).unwrap();
+        assert!(untightened.container_height > 900.);
+
+        let result = compute(&input, &config).unwrap();
+        assert!(result.container_height <= 900.);
+        assert_inside(&result, &config, input.len());
+        assert_full_width(&result, &config);
+    }
+
+    #[test]
+    fn mixed_fits_min_container_aspect_ratio() {
+        let input = [1.5, 0.67, 1., 2., 0.5, 0.75, 1.33, 0.8, 1.78, 0.56];
+        let config = LayoutConfig {
+            container_width: 1300.,
+            target_row_height: vec![440., 500.],
+            target_row_height_tolerance: 0.1,
+            edge_case_min_row_height_factor: 0.8,
+            edge_case_max_row_height_factor: 1.5,
+            min_container_aspect_ratio: Some(1.),
+            ..Default::default()
+        };
+        let untightened = LayoutConfig {
+            min_container_aspect_ratio: None,
+            ..config.clone()
+        };
+        assert!(compute(&input, &untightened).unwrap().container_height > 1300.);
+
+        let result = compute(&input, &config).unwrap();
+        assert!(result.container_height <= 1300.);
+        assert_inside(&result, &config, input.len());
+        assert_full_width(&result, &config);
+    }
+
+    #[test]
+    fn single_item_fits() {
+        let config = LayoutConfig {
+            max_container_height: Some(200.),
+            ..Default::default()
+        };
+        let result = compute(&[0.3], &config).unwrap();
+        assert!(result.container_height <= 200.);
+        assert_inside(&result, &config, 1);
+    }
+
+    #[test]
+    fn panorama_fits_by_tightening() {
+        // Too wide for a row of its own, then a widow at the shorter target height
+        let config = LayoutConfig {
+            max_container_height: Some(100.),
+            ..Default::default()
+        };
+        let untightened = compute(&[10.], &LayoutConfig::default()).unwrap();
+        assert!(untightened.container_height > 100.);
+
+        let result = compute(&[10.], &config).unwrap();
+        assert!(result.container_height <= 100.);
+        assert_inside(&result, &config, 1);
+        let item = result.boxes[0];
+        assert!((item.width / item.height - 10.).abs() < 1e-6);
+    }
+
+    #[test]
+    fn shrinks_masonry_that_cant_take_more_columns() {
+        // One box is one column however many we ask for
+        let config = LayoutConfig {
+            algorithm: LayoutAlgorithm::Masonry { columns: 3 },
+            max_container_height: Some(1000.),
+            ..Default::default()
+        };
+        let result = compute(&[0.5], &config).unwrap();
+        assert_eq!(result.container_height, 1000.);
+        assert_inside(&result, &config, 1);
+        let item = result.boxes[0];
+        assert!((item.height - 980.).abs() < 1e-6);
+        assert!((item.width - 490.).abs() < 1e-6);
+        // Centered
+        assert!((item.left + item.width / 2. - 530.).abs() < 1e-6);
+    }
+}
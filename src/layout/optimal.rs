@@ -0,0 +1,236 @@
+// Justified rows like `Justified`, but the breaks are chosen for the whole set at once,
+// Knuth–Plass style: dynamic programming over break points, minimizing the squared
+// relative deviation of each row's height from its target. A short last row is free,
+// it becomes a widow at its target height. Breakout rows aren't supported.
+use super::{Layout, LayoutConfig, LayoutItem, LayoutResult, LayoutState, WidowLayoutStyle};
+use anyhow::Result;
+
+/// Added for each row outside the edge case heights, so those only happen if nothing else fits
+const OUT_OF_RANGE_PENALTY: f64 = 1e6;
+
+pub struct Optimal;
+
+impl Layout for Optimal {
+    fn compute(&self, input: &[f64], config: &LayoutConfig) -> Result<LayoutResult> {
+        let items: Vec<LayoutItem> = input
+            .iter()
+            .map(|&ar| LayoutItem {
+                aspect_ratio: config.force_aspect_ratio.unwrap_or(ar),
+                forced_aspect_ratio: config.force_aspect_ratio.is_some(),
+                ..Default::default()
+            })
+            .collect();
+        let count = items.len();
+        let max_rows = config.max_num_rows.min(count);
+
+        // best[k][j]: cheapest way to put the first j items in k rows,
+        // and where the last of those rows starts
+        let mut best = vec![vec![(f64::INFINITY, 0); count + 1]; max_rows + 1];
+        best[0][0] = (0., 0);
+        for k in 1..=max_rows {
+            let target = target_row_height(config, k - 1);
+            for j in 1..=count {
+                let mut ratio_sum = 0.;
+                for i in (0..j).rev() {
+                    ratio_sum += items[i].aspect_ratio;
+                    let (previous, _) = best[k - 1][i];
+                    if previous.is_infinite() {
+                        continue;
+                    }
+                    let height = row_height(config, ratio_sum, j - i);
+                    let cost = previous + row_cost(config, height, target, j == count);
+                    if cost < best[k][j].0 {
+                        best[k][j] = (cost, i);
+                    }
+                }
+            }
+        }
+
+        let rows = (0..=max_rows)
+            .min_by(|&a, &b| best[a][count].0.total_cmp(&best[b][count].0))
+            .unwrap_or(0);
+        let mut breaks = Vec::with_capacity(rows);
+        let mut end = count;
+        for k in (1..=rows).rev() {
+            let start = best[k][end].1;
+            breaks.push((start, end));
+            end = start;
+        }
+        breaks.reverse();
+
+        let mut state = LayoutState {
+            container_height: config.container_padding.top,
+            rows: Vec::new(),
+        };
+        let mut laid_out_items = Vec::with_capacity(count);
+        let mut widow_count = 0;
+        for (start, end) in breaks {
+            let mut row = state.create_new_row(config);
+            row.is_breakout_row = false;
+            row.items.extend_from_slice(&items[start..end]);
+            let ratio_sum = items[start..end].iter().map(|x| x.aspect_ratio).sum();
+            let height = row_height(config, ratio_sum, end - start);
+            if is_widow(config, height, row.target_row_height, end == count) {
+                row.force_complete(false, None);
+                widow_count = end - start;
+            } else {
+                row.complete_layout(height, Some(WidowLayoutStyle::Justify));
+            }
+            laid_out_items.extend(state.add_row(config, row));
+        }
+
+        // Cleanup bottom padding
+        state.container_height -= config.box_spacing.vertical;
+        state.container_height += config.container_padding.bottom;
+
+        Ok(LayoutResult {
+            container_height: state.container_height,
+            widow_count,
+            boxes: laid_out_items,
+        })
+    }
+}
+
+fn target_row_height(config: &LayoutConfig, row: usize) -> f64 {
+    config.target_row_height[row % config.target_row_height.len()]
+}
+
+/// Height at which `len` items with these aspect ratios fill the row exactly
+fn row_height(config: &LayoutConfig, ratio_sum: f64, len: usize) -> f64 {
+    let width = config.container_width
+        - config.container_padding.left
+        - config.container_padding.right
+        - config.box_spacing.horizontal * (len - 1) as f64;
+    width / ratio_sum
+}
+
+/// A last row that would have to be stretched taller than its target isn't full
+fn is_widow(config: &LayoutConfig, height: f64, target: f64, is_last: bool) -> bool {
+    is_last && config.show_widows && height > target
+}
+
+fn row_cost(config: &LayoutConfig, height: f64, target: f64, is_last: bool) -> f64 {
+    if is_widow(config, height, target, is_last) {
+        return 0.;
+    }
+    let deviation = (height - target) / target;
+    let mut cost = deviation * deviation;
+    if height < config.edge_case_min_row_height_factor * target
+        || height > config.edge_case_max_row_height_factor * target
+    {
+        cost += OUT_OF_RANGE_PENALTY;
+    }
+    cost
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::layout::Justified;
+
+    /// Row lengths of a result, rows are told apart by their top
+    fn row_lengths(result: &LayoutResult) -> Vec<usize> {
+        let mut lengths: Vec<usize> = vec![];
+        let mut top = f64::NAN;
+        for item in &result.boxes {
+            if item.top == top {
+                *lengths.last_mut().unwrap() += 1;
+            } else {
+                lengths.push(1);
+                top = item.top;
+            }
+        }
+        lengths
+    }
+
+    fn cost(config: &LayoutConfig, input: &[f64], lengths: &[usize]) -> f64 {
+        let mut start = 0;
+        let mut total = 0.;
+        for (k, &len) in lengths.iter().enumerate() {
+            let end = start + len;
+            let height = row_height(config, input[start..end].iter().sum(), len);
+            let target = target_row_height(config, k);
+            total += row_cost(config, height, target, end == input.len());
+            start = end;
+        }
+        total
+    }
+
+    /// The cheapest split of `input` into rows, trying all of them
+    fn brute_force(config: &LayoutConfig, input: &[f64]) -> f64 {
+        let gaps = input.len() - 1;
+        (0..1u32 << gaps)
+            .map(|breaks| {
+                let mut lengths = vec![1];
+                for gap in 0..gaps {
+                    if breaks & (1 << gap) != 0 {
+                        lengths.push(1);
+                    } else {
+                        *lengths.last_mut().unwrap() += 1;
+                    }
+                }
+                cost(config, input, &lengths)
+            })
+            .fold(f64::INFINITY, f64::min)
+    }
+
+    #[test]
+    fn finds_the_cheapest_breaks() {
+        let config = LayoutConfig::default();
+        let inputs: [&[f64]; 4] = [
+            &[0.5, 1.5, 1., 1.8, 0.4, 0.7, 0.9, 1.1, 1.7, 2.],
+            &[1., 1., 1., 1., 1.],
+            &[2.5, 0.6, 0.6, 2.5, 0.6, 0.6, 1.3],
+            &[3., 0.5, 0.5, 0.5, 3., 1.],
+        ];
+        for input in inputs {
+            let result = Optimal.compute(input, &config).unwrap();
+            let lengths = row_lengths(&result);
+            assert_eq!(lengths.iter().sum::<usize>(), input.len());
+            let found = cost(&config, input, &lengths);
+            assert!(
+                (found - brute_force(&config, input)).abs() < 1e-9,
+                "{input:?} split into {lengths:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn beats_greedy_rows() {
+        let config = LayoutConfig::default();
+        let input = [2.5, 0.6, 0.6, 2.5, 0.6, 0.6, 1.3];
+        let greedy = Justified.compute(&input, &config).unwrap();
+        let optimal = Optimal.compute(&input, &config).unwrap();
+        let greedy_cost = cost(&config, &input, &row_lengths(&greedy));
+        let optimal_cost = cost(&config, &input, &row_lengths(&optimal));
+        assert!(optimal_cost < greedy_cost);
+    }
+
+    #[test]
+    fn full_rows_fill_the_width() {
+        let config = LayoutConfig::default();
+        let input = [0.5, 1.5, 1., 1.8, 0.4, 0.7, 0.9, 1.1, 1.7, 2.];
+        let result = Optimal.compute(&input, &config).unwrap();
+        let right = config.container_width - config.container_padding.right;
+        let lengths = row_lengths(&result);
+        let mut start = 0;
+        for (k, len) in lengths.iter().enumerate() {
+            let row = &result.boxes[start..start + len];
+            let last = row.last().unwrap();
+            if k + 1 < lengths.len() || result.widow_count == 0 {
+                assert!((last.left + last.width - right).abs() <= 1.);
+            }
+            start += len;
+        }
+    }
+
+    #[test]
+    fn short_last_row_is_a_widow_at_target_height() {
+        let config = LayoutConfig::default();
+        let result = Optimal.compute(&[1., 1., 1., 1.], &config).unwrap();
+        assert_eq!(row_lengths(&result), [3, 1]);
+        assert_eq!(result.widow_count, 1);
+        assert_eq!(result.boxes[3].height, 320.);
+        assert_eq!(result.boxes[3].width, 320.);
+    }
+}
@@ -181,8 +181,22 @@ impl Row {
         }
     }
 
-    pub fn force_complete(&mut self, _fit_to_width: bool, row_height: Option<f64>) {
-        if let Some(height) = row_height {
+    /// Lay out a row that never filled up. `fit_to_width` justifies it across the full
+    /// width like any other row, otherwise it gets `row_height`, or the target height, and
+    /// `widow_layout_style`. An empty row has nothing to lay out.
+    pub fn force_complete(&mut self, fit_to_width: bool, row_height: Option<f64>) {
+        if self.items.is_empty() {
+            return;
+        }
+        if fit_to_width {
+            let row_width_without_spacing =
+                self.width - self.spacing * (self.items.len() - 1) as f64;
+            let aspect_ratio: f64 = self.items.iter().map(|x| x.aspect_ratio).sum();
+            self.complete_layout(
+                row_width_without_spacing / aspect_ratio,
+                Some(WidowLayoutStyle::Justify),
+            );
+        } else if let Some(height) = row_height {
             self.complete_layout(height, Some(self.widow_layout_style));
         } else {
             self.complete_layout(self.target_row_height, Some(self.widow_layout_style));
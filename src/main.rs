@@ -1,11 +1,16 @@
 #![feature(try_blocks)]
+mod composite;
 mod config;
 mod db;
+mod download;
+mod extractor;
 mod handlers;
+mod image_cache;
 mod layout;
 mod models;
 mod services;
 mod store;
+mod tg_embed;
 mod utils;
 
 use crate::config::SESSION_FILE;
@@ -23,6 +28,8 @@ use tokio::task;
 #[tokio::main]
 async fn main() -> Result<()> {
     tracing_subscriber::fmt::init();
+    // A typo in a setting should stop us here, not panic the first pull
+    config::init()?;
 
     // 1. Precise Environment Handling
     // We use .context() so you know EXACTLY which variable is missing.
@@ -68,8 +75,15 @@ async fn main() -> Result<()> {
     tracing::info!("Logged in as @{}", me.username().unwrap_or("unknown"));
 
     // 6. Global State Injection
+    // Bad extractor rules should stop us here, not on the first channel post
+    let rules = extractor::reload_rules()?;
+    tracing::info!("Loaded {} extractor rules", rules);
     STORE.init(client.clone()).await?;
     tracing::info!("Store ready");
+    services::characters::init().await?;
+    tokio::spawn(services::catalog::catch_up());
+    // Scan the image cache now rather than during the first ten pull
+    std::sync::LazyLock::force(&image_cache::IMAGE_CACHE);
 
     // 7. Robust Event Loop
     let mut update_stream = client.stream_updates(updates, Default::default()).await;
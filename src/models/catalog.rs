@@ -0,0 +1,14 @@
+use chrono::{DateTime, Utc};
+
+/// A row of the channel catalog
+#[derive(Clone, Debug)]
+pub struct ChannelPost {
+    pub post_id: i32,
+    /// Empty if no extractor rule matched
+    pub characters: Vec<String>,
+    pub series: Option<String>,
+    pub artist: Option<String>,
+    pub photo_id: Option<i64>,
+    pub posted_at: DateTime<Utc>,
+    pub valid: bool,
+}
@@ -0,0 +1,47 @@
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Lang {
+    Zh,
+    Ja,
+    En,
+}
+
+impl Lang {
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "zh" => Some(Self::Zh),
+            "ja" => Some(Self::Ja),
+            "en" => Some(Self::En),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Character {
+    pub id: i64,
+    /// The raw name the character was first seen as
+    pub canonical_name: String,
+    pub name_zh: Option<String>,
+    pub name_ja: Option<String>,
+    pub name_en: Option<String>,
+    /// Kana reading of the names, kanji can't be romanized without it
+    pub reading: Option<String>,
+}
+
+impl Character {
+    pub fn name(&self, lang: Lang) -> Option<&str> {
+        match lang {
+            Lang::Zh => self.name_zh.as_deref(),
+            Lang::Ja => self.name_ja.as_deref(),
+            Lang::En => self.name_en.as_deref(),
+        }
+    }
+
+    /// Chinese first, it's a Chinese bot
+    pub fn display_name(&self) -> &str {
+        [Lang::Zh, Lang::Ja, Lang::En]
+            .into_iter()
+            .find_map(|lang| self.name(lang))
+            .unwrap_or(&self.canonical_name)
+    }
+}
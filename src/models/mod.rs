@@ -1,2 +1,4 @@
+pub mod catalog;
+pub mod character;
 pub mod prize;
 pub mod user;
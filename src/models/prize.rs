@@ -1,18 +1,81 @@
+use crate::image_cache::ImageKey;
 use bytes::Bytes;
 use grammers_client::media::Photo;
+use grammers_tl_types::enums::MessageEntity;
+use grammers_tl_types::types::MessageEntityTextUrl;
 use serde::{Deserialize, Serialize};
+use std::str::FromStr;
 
 #[derive(Clone, Debug)]
 pub struct Prize {
-    pub name: String,
+    /// At least one, a post may feature several characters
+    pub characters: Vec<String>,
+    pub series: Option<String>,
     pub url: String,
     pub photo: PrizePhoto,
+    /// Smaller renditions of a `PrizePhoto::Url`, for composite tiles. Empty if unknown.
+    pub variants: Vec<PhotoVariant>,
+    /// Where the photo lives in `image_cache`, None if it can't be cached
+    pub image_key: Option<ImageKey>,
+}
+
+#[derive(Clone, Debug)]
+pub struct PhotoVariant {
+    pub url: String,
+    pub width: u32,
+    pub height: u32,
+    /// "jpg", "webp", ...
+    pub file_ext: String,
+}
+
+impl Prize {
+    /// "A & B", for logs and plain text
+    pub fn display_name(&self) -> String {
+        self.characters.join(" & ")
+    }
+
+    /// "[A](url) & [B](url)", see the markdown caveats in `handlers::handle_inline_send`
+    pub fn markdown_names(&self) -> String {
+        self.characters
+            .iter()
+            .map(|name| format!("[{}]({})", name, self.url))
+            .collect::<Vec<_>>()
+            .join(" & ")
+    }
+
+    /// `markdown_names` as text and entities, for messages we can't pass through markdown
+    pub fn linked_names(&self) -> (String, Vec<MessageEntity>) {
+        let mut text = String::new();
+        let mut entities = vec![];
+        self.push_linked_names(&mut text, &mut entities);
+        (text, entities)
+    }
+
+    /// Append the linked names to a message that is being built
+    pub fn push_linked_names(&self, text: &mut String, entities: &mut Vec<MessageEntity>) {
+        for (i, name) in self.characters.iter().enumerate() {
+            if i > 0 {
+                text.push_str(" & ");
+            }
+            entities.push(MessageEntity::TextUrl(MessageEntityTextUrl {
+                offset: text.encode_utf16().count() as i32,
+                length: name.encode_utf16().count() as i32,
+                url: self.url.clone(),
+            }));
+            text.push_str(name);
+        }
+    }
 }
 
 #[derive(Clone, Debug)]
 pub enum PrizePhoto {
     TelegramPhoto(Photo),
-    File { name: String, content: Bytes },
+    File {
+        name: String,
+        content: Bytes,
+        /// Upload as a document, Telegram won't take it as a photo
+        as_document: bool,
+    },
     Url(String),
 }
 
@@ -22,3 +85,38 @@ pub enum PrizeSource {
     File { file_name: String },
     Url { photo_url: String },
 }
+
+/// Where a pull may be served from, tried in the order of `config::FALLBACK_CHAIN`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PullSource {
+    /// The user's special prize, searched on Danbooru
+    Special,
+    /// A random post from channel @WaifuP1c
+    Channel,
+    /// Prizes we have already resolved, from `Store::prizes`
+    LocalPool,
+}
+
+impl PullSource {
+    /// The name used in `PULL_FALLBACK_CHAIN` and the database
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Special => "special",
+            Self::Channel => "channel",
+            Self::LocalPool => "local",
+        }
+    }
+}
+
+impl FromStr for PullSource {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim() {
+            "special" => Ok(Self::Special),
+            "channel" => Ok(Self::Channel),
+            "local" => Ok(Self::LocalPool),
+            other => Err(anyhow::anyhow!("Unknown pull source '{other}'")),
+        }
+    }
+}
@@ -0,0 +1,215 @@
+use crate::db::Database;
+use crate::image_cache::{IMAGE_CACHE, ImageKey};
+use crate::models::catalog::ChannelPost;
+use crate::models::prize::Prize;
+use crate::services::channel::get_channel_max_post_id;
+use crate::services::characters;
+use crate::store::{
+    MAX_MESSAGES_PER_REQUEST, STORE, fetch_channel_messages, parse_channel_message,
+};
+use anyhow::Result;
+use grammers_client::message::Message;
+use std::time::Duration;
+
+/// A post that failed this many times is left alone, until an edit brings it back
+const MAX_INDEX_ATTEMPTS: i64 = 5;
+
+/// `catch_up` retries with exponential backoff between these
+const CATCH_UP_RETRY_MIN: Duration = Duration::from_secs(60);
+const CATCH_UP_RETRY_MAX: Duration = Duration::from_secs(60 * 60);
+
+/// Parse a channel post and write it to the catalog
+pub async fn index_message(db: &Database, msg: &Message) -> Result<Option<Prize>> {
+    let parsed = parse_channel_message(msg).await?;
+    let photo_id = msg.photo().map(|photo| photo.id());
+    match &parsed {
+        Some((_, extracted)) => {
+            tracing::debug!(post_id = msg.id(), rule = %extracted.rule, "Extracted {:?}", extracted.characters)
+        }
+        None if photo_id.is_some() => tracing::info!("No extractor rule matched post {}", msg.id()),
+        None => {}
+    }
+    let extracted = parsed.as_ref().map(|(_, extracted)| extracted);
+    let post = ChannelPost {
+        post_id: msg.id(),
+        characters: extracted.map_or(vec![], |extracted| extracted.characters.clone()),
+        series: extracted.and_then(|extracted| extracted.series.clone()),
+        artist: extracted.and_then(|extracted| extracted.artist.clone()),
+        photo_id,
+        posted_at: msg.date(),
+        valid: parsed.is_some(),
+    };
+    characters::register(db, &post.characters).await?;
+    db.upsert_channel_post(&post).await?;
+    Ok(parsed.map(|(prize, _)| prize))
+}
+
+/// Index every post after the last indexed one, up to `max_post_id`, and try the posts
+/// that failed before again. The first run walks the whole channel history.
+/// A failed request stops the run, the next one starts where it stopped.
+#[tracing::instrument]
+pub async fn backfill(max_post_id: i32) -> Result<usize> {
+    let (db, client, channel) = {
+        let store = STORE.get().await?;
+        (
+            store.database(),
+            store.client.clone(),
+            store.waifu_pic_channel,
+        )
+    };
+    let retries = db.failed_channel_posts(MAX_INDEX_ATTEMPTS).await?;
+    let start = db.max_indexed_post_id().await?.unwrap_or(0) + 1;
+    if retries.is_empty() && start > max_post_id {
+        return Ok(0);
+    }
+    tracing::info!(
+        retries = retries.len(),
+        "Indexing channel posts {}..={}",
+        start,
+        max_post_id
+    );
+
+    let mut indexed = 0;
+    let post_ids = retries
+        .into_iter()
+        .filter(|&post_id| post_id < start)
+        .chain(start..=max_post_id)
+        .collect::<Vec<_>>();
+    for chunk in post_ids.chunks(MAX_MESSAGES_PER_REQUEST) {
+        let messages = fetch_channel_messages(&client, channel, chunk).await?;
+        for (&post_id, msg) in chunk.iter().zip(messages) {
+            let Some(msg) = msg else {
+                db.clear_index_failure(post_id).await?;
+                continue;
+            };
+            match index_message(&db, &msg).await {
+                Ok(_) => indexed += 1,
+                Err(e) => {
+                    tracing::warn!("Failed to index post {}: {:#}", post_id, e);
+                    db.record_index_failure(post_id, &format!("{e:#}")).await?;
+                }
+            }
+        }
+    }
+    tracing::info!("Indexed {} channel posts", indexed);
+    Ok(indexed)
+}
+
+/// What `reindex_unmatched` did with the posts no rule matched
+#[derive(Debug, Default)]
+pub struct Reindexed {
+    pub matched: usize,
+    pub unmatched: usize,
+    /// Gone from the channel
+    pub deleted: usize,
+    /// Left for the next catch up, see `record_index_failure`
+    pub failed: usize,
+}
+
+/// Run the extractors again over posts no rule matched, e.g. after editing the rules.
+/// One bad post doesn't stop the others.
+#[tracing::instrument]
+pub async fn reindex_unmatched() -> Result<Reindexed> {
+    let (db, client, channel) = {
+        let store = STORE.get().await?;
+        (
+            store.database(),
+            store.client.clone(),
+            store.waifu_pic_channel,
+        )
+    };
+    let post_ids = db.unmatched_channel_posts().await?;
+    let mut result = Reindexed::default();
+    for chunk in post_ids.chunks(MAX_MESSAGES_PER_REQUEST) {
+        let messages = fetch_channel_messages(&client, channel, chunk).await?;
+        for (&post_id, msg) in chunk.iter().zip(messages) {
+            let Some(msg) = msg else {
+                result.deleted += 1;
+                continue;
+            };
+            match index_message(&db, &msg).await {
+                Ok(Some(_)) => result.matched += 1,
+                Ok(None) => result.unmatched += 1,
+                Err(e) => {
+                    tracing::warn!("Failed to reindex post {}: {:#}", post_id, e);
+                    db.record_index_failure(post_id, &format!("{e:#}")).await?;
+                    result.failed += 1;
+                }
+            }
+        }
+    }
+    Ok(result)
+}
+
+/// Index whatever was posted while we were offline, retrying until nothing is left.
+/// Afterwards channel updates keep the catalog current.
+pub async fn catch_up() {
+    let mut delay = CATCH_UP_RETRY_MIN;
+    loop {
+        let result: Result<_> = try {
+            backfill(get_channel_max_post_id().await?).await?;
+            let db = STORE.get().await?.database();
+            db.failed_channel_posts(MAX_INDEX_ATTEMPTS).await?.len()
+        };
+        match result {
+            Ok(0) => return,
+            Ok(failed) => tracing::warn!(
+                "{} channel posts failed to index, retrying in {:?}",
+                failed,
+                delay
+            ),
+            Err(e) => tracing::error!(
+                "Failed to catch up with the channel, retrying in {:?}: {:#}",
+                delay,
+                e
+            ),
+        }
+        tokio::time::sleep(delay).await;
+        delay = (delay * 2).min(CATCH_UP_RETRY_MAX);
+    }
+}
+
+/// A post was published or edited in the channel
+#[tracing::instrument(skip(msg), fields(post_id = msg.id()))]
+pub async fn on_channel_post(msg: &Message) -> Result<()> {
+    let post_id = msg.id();
+    let db = {
+        let mut store = STORE.get().await?;
+        store.update_channel_max_post_id(post_id);
+        // the caption may have changed, so don't trust the cache
+        store.prizes.remove(&post_id);
+        store.database()
+    };
+    // Nor the cached tile, an edit may replace the photo
+    IMAGE_CACHE.remove(ImageKey::ChannelPost(post_id)).await;
+    let prize = match index_message(&db, msg).await {
+        Ok(prize) => prize,
+        Err(e) => {
+            // The next catch up tries again
+            db.record_index_failure(post_id, &format!("{e:#}")).await?;
+            return Err(e);
+        }
+    };
+    tracing::info!(valid = prize.is_some(), "Indexed channel post");
+    if let Some(prize) = prize {
+        STORE.get().await?.prizes.insert(post_id, prize);
+    }
+    Ok(())
+}
+
+/// Posts were deleted from the channel, stop handing them out
+#[tracing::instrument]
+pub async fn on_channel_posts_deleted(post_ids: &[i32]) -> Result<()> {
+    let db = {
+        let mut store = STORE.get().await?;
+        for post_id in post_ids {
+            store.prizes.remove(post_id);
+        }
+        store.database()
+    };
+    for &post_id in post_ids {
+        IMAGE_CACHE.remove(ImageKey::ChannelPost(post_id)).await;
+        db.set_channel_post_valid(post_id, false).await?;
+    }
+    Ok(())
+}
@@ -0,0 +1,236 @@
+use crate::config::{CHANNEL_USERNAME, HTTP_CLIENT};
+use crate::services::health::{Backend, HEALTH};
+use crate::store::{MAX_MESSAGES_PER_REQUEST, STORE, fetch_channel_messages};
+use anyhow::{Result, anyhow};
+use lol_html::{HtmlRewriter, Settings, element};
+use std::cell::RefCell;
+use std::rc::Rc;
+
+#[tracing::instrument]
+pub async fn get_channel_max_post_id() -> Result<i32> {
+    // Once known, channel updates keep it current, see `catalog::on_channel_post`
+    let (max_post_id, db) = {
+        let store = STORE.get().await?;
+        (store.channel_max_post_id, store.database())
+    };
+    if max_post_id > 0 {
+        return Ok(max_post_id);
+    }
+
+    tracing::info!("Loading max post id...");
+    let known = db.max_indexed_post_id().await?.unwrap_or(0);
+    let new_max_post_id = match get_max_post_id_mtproto(known).await {
+        Ok(Some(max_post_id)) => max_post_id,
+        result => {
+            if let Err(e) = result {
+                tracing::warn!("Failed to probe max post id: {:#}", e);
+            }
+            tracing::info!("Falling back to scraping t.me/s/{}", CHANNEL_USERNAME);
+            get_max_post_id_scrape().await?
+        }
+    };
+
+    tracing::info!("New max post id: {}", new_max_post_id);
+    STORE
+        .get()
+        .await?
+        .update_channel_max_post_id(new_max_post_id);
+    Ok(new_max_post_id)
+}
+
+/// Bots can't read channel history, but they can fetch posts by id.
+/// Probe windows of ids above `known` until one comes back empty.
+async fn get_max_post_id_mtproto(known: i32) -> Result<Option<i32>> {
+    let (client, channel) = {
+        let store = STORE.get().await?;
+        (store.client.clone(), store.waifu_pic_channel)
+    };
+    let max_post_id = probe_max_post_id(known, |post_ids| {
+        let client = client.clone();
+        async move {
+            let messages = fetch_channel_messages(&client, channel, &post_ids).await?;
+            Ok(messages.into_iter().flatten().map(|msg| msg.id()).max())
+        }
+    })
+    .await?;
+    Ok((max_post_id > 0).then_some(max_post_id))
+}
+
+/// `highest_existing` returns the highest id of the given ids that is still a post.
+/// Gallops upwards first and narrows back down from the window that came back empty,
+/// so an empty catalog doesn't cost a request per 100 posts, then walks window by window.
+/// Stops at a run of `MAX_MESSAGES_PER_REQUEST` missing ids.
+pub async fn probe_max_post_id<F, Fut>(known: i32, mut highest_existing: F) -> Result<i32>
+where
+    F: FnMut(Vec<i32>) -> Fut,
+    Fut: Future<Output = Result<Option<i32>>>,
+{
+    let window = MAX_MESSAGES_PER_REQUEST as i32;
+    let mut max_post_id = known;
+
+    let mut step = window;
+    loop {
+        let start = max_post_id + step;
+        match highest_existing((start..start + window).collect()).await? {
+            Some(found) => {
+                max_post_id = found;
+                step *= 2;
+            }
+            None => break,
+        }
+    }
+
+    while step > window {
+        step /= 2;
+        let start = max_post_id + step;
+        if let Some(found) = highest_existing((start..start + window).collect()).await? {
+            max_post_id = found;
+        }
+    }
+
+    loop {
+        let start = max_post_id + 1;
+        match highest_existing((start..start + window).collect()).await? {
+            Some(found) => max_post_id = found,
+            None => break,
+        }
+    }
+    Ok(max_post_id)
+}
+
+async fn get_max_post_id_scrape() -> Result<i32> {
+    let url = format!("https://t.me/s/{CHANNEL_USERNAME}");
+    let html = HEALTH
+        .track(Backend::TmeChannelPage, async {
+            Ok::<_, anyhow::Error>(HTTP_CLIENT.get(url).send().await?.text().await?)
+        })
+        .await?;
+    parse_max_post_id_from_channel_page(&html)?.ok_or(anyhow!("Cannot extract last post id"))
+}
+
+/// Every post on t.me/s/<channel> carries `data-post="<channel>/<post_id>"`,
+/// the last one is the newest.
+pub fn parse_max_post_id_from_channel_page(html: &str) -> Result<Option<i32>> {
+    let max_post_id = Rc::new(RefCell::new(None::<i32>));
+
+    let mut rewriter = HtmlRewriter::new(
+        Settings {
+            element_content_handlers: vec![element!(".tgme_widget_message[data-post]", |el| {
+                let post_id = el
+                    .get_attribute("data-post")
+                    .and_then(|attr| attr.rsplit_once('/')?.1.trim().parse::<i32>().ok());
+                if let Some(post_id) = post_id {
+                    let mut max_post_id = max_post_id.borrow_mut();
+                    *max_post_id = Some(max_post_id.map_or(post_id, |max| max.max(post_id)));
+                }
+                Ok(())
+            })],
+            ..Settings::default()
+        },
+        |_: &[u8]| {},
+    );
+
+    rewriter.write(html.as_bytes())?;
+    rewriter.end()?;
+
+    Ok(*max_post_id.borrow())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeSet;
+
+    /// A channel where `posts` exist, counting the requests made to it
+    struct FakeChannel {
+        posts: BTreeSet<i32>,
+        requests: usize,
+    }
+
+    impl FakeChannel {
+        fn new(posts: impl IntoIterator<Item = i32>) -> Self {
+            Self {
+                posts: posts.into_iter().collect(),
+                requests: 0,
+            }
+        }
+
+        async fn probe(&mut self, known: i32) -> i32 {
+            let posts = self.posts.clone();
+            let requests = &mut self.requests;
+            probe_max_post_id(known, |post_ids| {
+                assert!(post_ids.len() <= MAX_MESSAGES_PER_REQUEST);
+                *requests += 1;
+                let found = post_ids.into_iter().filter(|id| posts.contains(id)).max();
+                async move { Ok(found) }
+            })
+            .await
+            .unwrap()
+        }
+    }
+
+    #[tokio::test]
+    async fn probe_empty_channel() {
+        let mut channel = FakeChannel::new([]);
+        assert_eq!(channel.probe(0).await, 0);
+    }
+
+    #[tokio::test]
+    async fn probe_fewer_posts_than_a_window() {
+        let mut channel = FakeChannel::new(1..=42);
+        assert_eq!(channel.probe(0).await, 42);
+    }
+
+    #[tokio::test]
+    async fn probe_across_gaps() {
+        // Deleted posts leave holes, shorter than a window
+        let posts = (1..=950).filter(|id| id % 7 != 0 && !(300..380).contains(id));
+        let mut channel = FakeChannel::new(posts);
+        assert_eq!(channel.probe(0).await, 950);
+        assert_eq!(channel.probe(500).await, 950);
+    }
+
+    #[tokio::test]
+    async fn probe_large_channel() {
+        let mut channel = FakeChannel::new(1..=25_000);
+        assert_eq!(channel.probe(0).await, 25_000);
+        // Galloping, not 250 windows
+        assert!(channel.requests < 40, "{} requests", channel.requests);
+
+        let mut channel = FakeChannel::new(1..=25_000);
+        assert_eq!(channel.probe(24_990).await, 25_000);
+        assert!(channel.requests <= 3, "{} requests", channel.requests);
+    }
+
+    #[tokio::test]
+    async fn probe_stops_at_known_when_nothing_is_new() {
+        let mut channel = FakeChannel::new(1..=300);
+        assert_eq!(channel.probe(300).await, 300);
+    }
+
+    #[test]
+    fn parse_channel_page() {
+        let html = include_str!("../../fixtures/tme/channel_page.html");
+        assert_eq!(
+            parse_max_post_id_from_channel_page(html).unwrap(),
+            Some(12843)
+        );
+    }
+
+    #[test]
+    fn parse_channel_page_without_posts() {
+        let html = include_str!("../../fixtures/tme/channel_page_empty.html");
+        assert_eq!(parse_max_post_id_from_channel_page(html).unwrap(), None);
+        assert_eq!(parse_max_post_id_from_channel_page("").unwrap(), None);
+    }
+
+    #[test]
+    fn parse_channel_page_ignores_malformed_post_ids() {
+        let html = r#"
+            <div class="tgme_widget_message" data-post="WaifuP1c/"></div>
+            <div class="tgme_widget_message" data-post="WaifuP1c"></div>
+            <div class="tgme_widget_message" data-post="AnotherChannelName/77"></div>
+        "#;
+        assert_eq!(parse_max_post_id_from_channel_page(html).unwrap(), Some(77));
+    }
+}
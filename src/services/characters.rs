@@ -0,0 +1,238 @@
+// Canonical characters and their aliases.
+// Channel hashtags for one character vary (#初音ミク, #HatsuneMiku, #miku), so everything
+// that shows or counts characters goes through `display_name`/`character_of` instead of
+// using raw names. The catalog keeps raw names, collections are keyed by character id.
+use crate::db::Database;
+use crate::models::character::{Character, Lang};
+use crate::services::search;
+use crate::store::STORE;
+use anyhow::{Result, anyhow};
+use std::collections::HashMap;
+use std::sync::{Arc, LazyLock, RwLock};
+
+/// key: normalized alias
+static ALIASES: LazyLock<RwLock<HashMap<String, Arc<Character>>>> = LazyLock::new(Default::default);
+/// key: character id, the characters of `ALIASES`
+static CHARACTERS: LazyLock<RwLock<HashMap<i64, Arc<Character>>>> = LazyLock::new(Default::default);
+
+/// `#Miku ` and `miku` are the same alias
+pub fn normalize(name: &str) -> String {
+    name.trim().trim_start_matches('#').to_lowercase()
+}
+
+pub fn character_of(name: &str) -> Option<Arc<Character>> {
+    ALIASES.read().unwrap().get(&normalize(name)).cloned()
+}
+
+/// The name to show for a raw name, the raw name itself if we don't know it yet
+pub fn display_name(name: &str) -> String {
+    character_of(name).map_or(name.trim().to_owned(), |character| {
+        character.display_name().to_owned()
+    })
+}
+
+/// Display names with duplicates removed, e.g. a post tagged both #miku and #初音ミク
+pub fn display_names(names: &[String]) -> Vec<String> {
+    let mut result: Vec<String> = vec![];
+    for name in names {
+        let name = display_name(name);
+        if !result.contains(&name) {
+            result.push(name);
+        }
+    }
+    result
+}
+
+/// Every known character, deduplicated
+pub fn all_characters() -> Vec<Arc<Character>> {
+    CHARACTERS.read().unwrap().values().cloned().collect()
+}
+
+pub fn character_by_id(character_id: i64) -> Option<Arc<Character>> {
+    CHARACTERS.read().unwrap().get(&character_id).cloned()
+}
+
+/// (character_id, alias) for every alias of the characters, in one pass over the aliases
+pub fn alias_pairs(character_ids: &[i64]) -> Vec<(i64, String)> {
+    let aliases = ALIASES.read().unwrap();
+    aliases
+        .iter()
+        .filter(|(_, character)| character_ids.contains(&character.id))
+        .map(|(alias, character)| (character.id, alias.clone()))
+        .collect()
+}
+
+pub fn aliases_of(character_id: i64) -> Vec<String> {
+    let aliases = ALIASES.read().unwrap();
+    let mut result = aliases
+        .iter()
+        .filter(|(_, character)| character.id == character_id)
+        .map(|(alias, _)| alias.clone())
+        .collect::<Vec<_>>();
+    result.sort();
+    result
+}
+
+/// Character ids of raw or display names, without duplicates. Unknown names are skipped,
+/// `register` them first.
+pub fn ids_of(names: &[String]) -> Vec<i64> {
+    let mut result = vec![];
+    for name in names {
+        if let Some(character) = character_of(name)
+            && !result.contains(&character.id)
+        {
+            result.push(character.id);
+        }
+    }
+    result
+}
+
+/// Credit a prize's characters to the user's collection, registering names we haven't
+/// seen, e.g. a special prize's display name
+pub async fn credit(db: &Database, user_id: i64, names: &[String]) -> Result<()> {
+    register(db, names).await?;
+    db.credit_collection(user_id, &ids_of(names)).await
+}
+
+async fn reload(db: &Database) -> Result<()> {
+    let aliases = db.get_character_aliases().await?;
+    let mut characters: HashMap<i64, Arc<Character>> = HashMap::new();
+    let mut map = HashMap::with_capacity(aliases.len());
+    for (alias, character) in aliases {
+        let character = characters
+            .entry(character.id)
+            .or_insert_with(|| Arc::new(character))
+            .clone();
+        map.insert(alias, character);
+    }
+    search::rebuild(&map);
+    *ALIASES.write().unwrap() = map;
+    *CHARACTERS.write().unwrap() = characters;
+    Ok(())
+}
+
+/// Make sure every raw name has an alias, creating a character for unknown ones
+pub async fn register(db: &Database, names: &[String]) -> Result<()> {
+    let mut unknown = false;
+    for name in names {
+        let alias = normalize(name);
+        let known = ALIASES.read().unwrap().contains_key(&alias);
+        if alias.is_empty() || known {
+            continue;
+        }
+        // False if a concurrent register got there first, reload all the same so the
+        // caller sees the alias without waiting for that one
+        db.create_character(name.trim().trim_start_matches('#'), &alias)
+            .await?;
+        unknown = true;
+    }
+    if unknown {
+        reload(db).await?;
+    }
+    Ok(())
+}
+
+/// Load aliases, registering names seen in the catalog and collections before aliases existed
+pub async fn init() -> Result<()> {
+    let db = STORE.get().await?.database();
+    reload(&db).await?;
+    let names = db.get_all_character_names().await?;
+    register(&db, &names).await?;
+    migrate_legacy_collections(&db).await?;
+    backfill_catalog_aliases(&db).await?;
+    tracing::info!("Loaded {} characters", all_characters().len());
+    Ok(())
+}
+
+/// Collections used to be keyed by raw name. The SQL migration moves rows it can match to
+/// an alias, this moves the rest, whose normalization needs Rust's Unicode lowercasing.
+async fn migrate_legacy_collections(db: &Database) -> Result<()> {
+    let names = db.get_legacy_collection_names().await?;
+    for name in &names {
+        match character_of(name) {
+            Some(character) => db.migrate_legacy_collection(name, character.id).await?,
+            None => tracing::warn!("Collection name {:?} has no character, left as is", name),
+        }
+    }
+    if !names.is_empty() {
+        tracing::info!("Migrated collections of {} names", names.len());
+    }
+    Ok(())
+}
+
+/// Catalog rows from before they kept their alias, which needs Rust's Unicode lowercasing
+/// like `migrate_legacy_collections`
+async fn backfill_catalog_aliases(db: &Database) -> Result<()> {
+    let names = db.get_unaliased_channel_post_characters().await?;
+    for name in &names {
+        db.set_channel_post_character_alias(name, &normalize(name))
+            .await?;
+    }
+    if !names.is_empty() {
+        tracing::info!("Filled in aliases of {} catalog names", names.len());
+    }
+    Ok(())
+}
+
+/// Names changed, cached prizes have stale display names
+async fn invalidate(db: &Database) -> Result<()> {
+    reload(db).await?;
+    STORE.get().await?.prizes.clear();
+    Ok(())
+}
+
+/// Make `alias`'s character and all its aliases part of `target`'s character
+pub async fn merge(alias: &str, target: &str) -> Result<Arc<Character>> {
+    let from = character_of(alias).ok_or(anyhow!("Unknown alias {alias}"))?;
+    let into = character_of(target).ok_or(anyhow!("Unknown alias {target}"))?;
+    if from.id == into.id {
+        return Err(anyhow!(
+            "{alias} and {target} are already the same character"
+        ));
+    }
+    let db = STORE.get().await?.database();
+    db.merge_characters(from.id, into.id).await?;
+    invalidate(&db).await?;
+    character_of(target).ok_or(anyhow!("Character vanished after merge"))
+}
+
+/// Detach `alias` into a character of its own
+pub async fn split(alias: &str) -> Result<Arc<Character>> {
+    let character = character_of(alias).ok_or(anyhow!("Unknown alias {alias}"))?;
+    if aliases_of(character.id).len() < 2 {
+        return Err(anyhow!("{alias} is the only alias of its character"));
+    }
+    let db = STORE.get().await?.database();
+    db.split_alias(&normalize(alias), alias.trim().trim_start_matches('#'))
+        .await?;
+    invalidate(&db).await?;
+    character_of(alias).ok_or(anyhow!("Character vanished after split"))
+}
+
+/// Set a localized display name, which also becomes an alias
+pub async fn set_name(alias: &str, lang: Lang, name: &str) -> Result<Arc<Character>> {
+    let character = character_of(alias).ok_or(anyhow!("Unknown alias {alias}"))?;
+    if let Some(other) = character_of(name)
+        && other.id != character.id
+    {
+        return Err(anyhow!(
+            "{name} already belongs to {}",
+            other.display_name()
+        ));
+    }
+    let db = STORE.get().await?.database();
+    db.set_character_name(character.id, lang, name, &normalize(name))
+        .await?;
+    invalidate(&db).await?;
+    character_of(alias).ok_or(anyhow!("Character vanished after rename"))
+}
+
+/// Set the kana reading search uses for kanji names
+pub async fn set_reading(alias: &str, reading: &str) -> Result<Arc<Character>> {
+    let character = character_of(alias).ok_or(anyhow!("Unknown alias {alias}"))?;
+    let db = STORE.get().await?.database();
+    db.set_character_reading(character.id, reading.trim())
+        .await?;
+    reload(&db).await?;
+    character_of(alias).ok_or(anyhow!("Character vanished after setting reading"))
+}
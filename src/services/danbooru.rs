@@ -1,45 +1,258 @@
 use crate::config::HTTP_CLIENT;
-use anyhow::{Result, anyhow};
+use crate::services::characters;
+use crate::services::health::{Backend, CircuitOpen, HEALTH};
+use anyhow::Result;
+use reqwest::StatusCode;
 use serde_json::Value;
 use std::env;
+use std::fmt;
+use std::sync::LazyLock;
+use std::time::Duration;
+use tokio::sync::Mutex;
+use tokio::time::Instant;
 
-use crate::models::prize::{Prize, PrizePhoto};
+use crate::image_cache::ImageKey;
+use crate::models::prize::{PhotoVariant, Prize, PrizePhoto};
+
+const DANBOORU_HOST: &str = "https://danbooru.donmai.us";
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+const MAX_ATTEMPTS: u32 = 4;
+const BASE_BACKOFF: Duration = Duration::from_millis(500);
+
+// Danbooru allows 10 read requests per second per account, so stay a bit below it.
+static RATE_LIMITER: LazyLock<TokenBucket> = LazyLock::new(|| TokenBucket::new(8, 8.0));
+
+#[derive(Debug, PartialEq)]
+pub enum DanbooruError {
+    /// The tag does not exist on Danbooru (probably a typo in the special prize seed)
+    UnknownTag(String),
+    /// Danbooru rejected our credentials, or they are not configured
+    AuthFailed,
+    /// Still being throttled after all retries
+    RateLimited,
+    /// The tag exists, but nothing matches our filters
+    NoResults(String),
+    /// Network errors, timeouts, 5xx after all retries and malformed responses
+    Unavailable(String),
+}
+
+impl fmt::Display for DanbooruError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::UnknownTag(tag) => write!(f, "Danbooru: unknown tag '{tag}'"),
+            Self::AuthFailed => write!(f, "Danbooru: authentication failed"),
+            Self::RateLimited => write!(f, "Danbooru: rate limited"),
+            Self::NoResults(tag) => write!(f, "Danbooru: no posts for '{tag}'"),
+            Self::Unavailable(reason) => write!(f, "Danbooru: unavailable ({reason})"),
+        }
+    }
+}
+
+impl std::error::Error for DanbooruError {}
+
+impl From<CircuitOpen> for DanbooruError {
+    fn from(e: CircuitOpen) -> Self {
+        Self::Unavailable(e.to_string())
+    }
+}
+
+impl DanbooruError {
+    /// Text shown to the user in place of the prize
+    pub fn user_message(&self) -> String {
+        match self {
+            Self::UnknownTag(tag) => format!("Danbooru 上找不到标签 {tag}，老婆迷路了"),
+            Self::AuthFailed => "Danbooru 不让我进去，请联系管理员".to_owned(),
+            Self::RateLimited => "Danbooru 觉得你抽得太快了，稍后再试吧".to_owned(),
+            Self::NoResults(tag) => format!("{tag} 暂时没有合适的图，过会儿再来吧"),
+            Self::Unavailable(_) => "Danbooru 好像挂了，稍后再试吧".to_owned(),
+        }
+    }
+}
+
+/// A simple token bucket. `acquire` waits until a token is available.
+struct TokenBucket {
+    capacity: f64,
+    refill_per_sec: f64,
+    state: Mutex<(f64, Instant)>,
+}
+
+impl TokenBucket {
+    fn new(capacity: u32, refill_per_sec: f64) -> Self {
+        Self {
+            capacity: capacity as f64,
+            refill_per_sec,
+            state: Mutex::new((capacity as f64, Instant::now())),
+        }
+    }
+
+    async fn acquire(&self) {
+        loop {
+            let wait = {
+                let mut state = self.state.lock().await;
+                match self.take(&mut state, Instant::now()) {
+                    Some(wait) => wait,
+                    None => return,
+                }
+            };
+            tokio::time::sleep(wait).await;
+        }
+    }
+
+    /// Refill for the time since the last call and take a token,
+    /// or how long to wait for one if there is none
+    fn take(&self, (tokens, last): &mut (f64, Instant), now: Instant) -> Option<Duration> {
+        *tokens = (*tokens + now.duration_since(*last).as_secs_f64() * self.refill_per_sec)
+            .min(self.capacity);
+        *last = now;
+        if *tokens >= 1.0 {
+            *tokens -= 1.0;
+            return None;
+        }
+        Some(Duration::from_secs_f64(
+            (1.0 - *tokens) / self.refill_per_sec,
+        ))
+    }
+}
+
+/// What to do with a response, judging by its status
+#[derive(Debug, PartialEq)]
+enum StatusAction {
+    /// Read the body
+    Proceed,
+    /// Back off and try again, the error stands if attempts run out
+    Retry(DanbooruError),
+    Fail(DanbooruError),
+}
+
+fn classify_status(status: StatusCode) -> StatusAction {
+    match status {
+        StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => {
+            StatusAction::Fail(DanbooruError::AuthFailed)
+        }
+        StatusCode::TOO_MANY_REQUESTS => StatusAction::Retry(DanbooruError::RateLimited),
+        s if s.is_server_error() => StatusAction::Retry(DanbooruError::Unavailable(s.to_string())),
+        _ => StatusAction::Proceed,
+    }
+}
+
+/// Danbooru reports errors as `{"success": false, "message": "..."}`
+fn check_body(status: StatusCode, body: Value) -> Result<Value, DanbooruError> {
+    if body["success"] == false || !status.is_success() {
+        let message = body["message"].as_str().unwrap_or("unknown error");
+        return Err(DanbooruError::Unavailable(format!("{status}: {message}")));
+    }
+    Ok(body)
+}
+
+/// Why a search came back empty
+fn empty_result_error(tag: &str, tag_exists: bool) -> DanbooruError {
+    if tag_exists {
+        DanbooruError::NoResults(tag.to_owned())
+    } else {
+        DanbooruError::UnknownTag(tag.to_owned())
+    }
+}
+
+async fn get_json(path: &str, params: &[(&str, &str)]) -> Result<Value, DanbooruError> {
+    HEALTH
+        .track(Backend::Danbooru, get_json_with_retry(path, params))
+        .await
+}
+
+/// GET a Danbooru JSON endpoint, with rate limiting and retries on 429/5xx/network errors.
+async fn get_json_with_retry(path: &str, params: &[(&str, &str)]) -> Result<Value, DanbooruError> {
+    let danbooru_user = env::var("DANBOORU_USER").map_err(|_| DanbooruError::AuthFailed)?;
+    let danbooru_key = env::var("DANBOORU_KEY").map_err(|_| DanbooruError::AuthFailed)?;
+
+    let mut last_error = DanbooruError::Unavailable("no attempt made".into());
+    for attempt in 0..MAX_ATTEMPTS {
+        if attempt > 0 {
+            let backoff = BASE_BACKOFF * 2u32.pow(attempt - 1);
+            tracing::debug!(attempt, ?backoff, "Retrying Danbooru request");
+            tokio::time::sleep(backoff).await;
+        }
+        RATE_LIMITER.acquire().await;
+
+        let response = HTTP_CLIENT
+            .get(format!("{DANBOORU_HOST}{path}"))
+            .query(params)
+            .basic_auth(&danbooru_user, Some(&danbooru_key))
+            .timeout(REQUEST_TIMEOUT)
+            .send()
+            .await;
+        let response = match response {
+            Ok(response) => response,
+            Err(e) => {
+                tracing::warn!("Danbooru request failed: {}", e);
+                last_error = DanbooruError::Unavailable(e.to_string());
+                continue;
+            }
+        };
+
+        let status = response.status();
+        match classify_status(status) {
+            StatusAction::Proceed => {}
+            StatusAction::Retry(e) => {
+                tracing::warn!("Danbooru returned {}", status);
+                last_error = e;
+                // Respect Retry-After if Danbooru told us how long to wait
+                if status == StatusCode::TOO_MANY_REQUESTS
+                    && let Some(retry_after) = response
+                        .headers()
+                        .get(reqwest::header::RETRY_AFTER)
+                        .and_then(|v| v.to_str().ok())
+                        .and_then(|v| v.parse::<u64>().ok())
+                {
+                    tokio::time::sleep(Duration::from_secs(retry_after.min(10))).await;
+                }
+                continue;
+            }
+            StatusAction::Fail(e) => return Err(e),
+        }
+
+        let body: Value = match response.json().await {
+            Ok(body) => body,
+            Err(e) => {
+                last_error = DanbooruError::Unavailable(e.to_string());
+                continue;
+            }
+        };
+        return check_body(status, body);
+    }
+    Err(last_error)
+}
+
+async fn tag_exists(tag: &str) -> Result<bool, DanbooruError> {
+    let response = get_json(
+        "/tags.json",
+        &[("search[name]", tag), ("search[hide_empty]", "false")],
+    )
+    .await?;
+    Ok(response.as_array().is_some_and(|tags| !tags.is_empty()))
+}
 
 #[tracing::instrument]
 pub async fn danbooru(tag: &str, display_name: &str, n: usize) -> Result<Vec<Prize>> {
     if tag.is_empty() {
-        return Err(anyhow!("tag is empty"));
+        return Err(DanbooruError::UnknownTag(tag.to_owned()).into());
     }
     tracing::info!("Searching Danbooru for '{}' (limit: {})", tag, n);
-    let host = "https://danbooru.donmai.us/posts.json";
     let tags = format!("-nude -ai-assisted -rating:e solo {tag}");
-    let params = [
-        ("tags", tags.as_ref()),
-        ("random", "1"),
-        ("limit", &n.to_string()),
-    ];
-    let danbooru_user = env::var("DANBOORU_USER").expect("DANBOORU_USER invalid");
-    let danbooru_key = env::var("DANBOORU_KEY").expect("DANBOORU_KEY invalid");
-
-    let response: Value = HTTP_CLIENT
-        .get(host)
-        .query(&params)
-        .basic_auth(danbooru_user, Some(danbooru_key))
-        .send()
-        .await?
-        .json()
-        .await?;
+    let limit = n.to_string();
+    let params = [("tags", tags.as_ref()), ("random", "1"), ("limit", &limit)];
+
+    let response = get_json("/posts.json", &params).await?;
 
     // Log response if debugging needed, but maybe too verbose for info
     // tracing::debug!("Danbooru response: {:?}", response);
 
-    let tasks = response
+    let prizes = response
         .as_array()
-        .ok_or(anyhow!("Missing root array"))?
+        .ok_or(DanbooruError::Unavailable("Missing root array".into()))?
         .iter()
         .filter_map(|post| {
             let danbooru_post_id = post["id"].as_u64()?;
-            let danbooru_post_url = format!("https://danbooru.donmai.us/posts/{danbooru_post_id}");
+            let danbooru_post_url = format!("{DANBOORU_HOST}/posts/{danbooru_post_id}");
 
             let variants = post["media_asset"]["variants"].as_array()?;
             let photo_url = variants
@@ -47,13 +260,128 @@ pub async fn danbooru(tag: &str, display_name: &str, n: usize) -> Result<Vec<Pri
                 .find(|item| item["type"] == "720x720")
                 .or_else(|| variants.get(0))
                 .and_then(|item| item["url"].as_str())?;
-            Some(Ok(Prize {
-                name: display_name.to_owned(),
+            // Originals may be videos or huge, the previews are enough for tiles
+            let variants = variants
+                .iter()
+                .filter(|item| item["type"] != "original")
+                .filter_map(|item| {
+                    Some(PhotoVariant {
+                        url: item["url"].as_str()?.to_owned(),
+                        width: item["width"].as_u64()? as u32,
+                        height: item["height"].as_u64()? as u32,
+                        file_ext: item["file_ext"].as_str()?.to_owned(),
+                    })
+                })
+                .collect();
+            Some(Prize {
+                characters: vec![characters::display_name(display_name)],
+                series: None,
                 url: danbooru_post_url,
                 photo: PrizePhoto::Url(photo_url.to_owned()),
-            }))
+                variants,
+                image_key: post["media_asset"]["id"]
+                    .as_u64()
+                    .map(ImageKey::DanbooruAsset),
+            })
         })
-        .collect();
+        .collect::<Vec<_>>();
+
+    if prizes.is_empty() {
+        return Err(empty_result_error(tag, tag_exists(tag).await?).into());
+    }
+
+    Ok(prizes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
 
-    tasks
+    #[test]
+    fn status_classification() {
+        let cases = [
+            (StatusCode::OK, StatusAction::Proceed),
+            (StatusCode::UNPROCESSABLE_ENTITY, StatusAction::Proceed),
+            (
+                StatusCode::UNAUTHORIZED,
+                StatusAction::Fail(DanbooruError::AuthFailed),
+            ),
+            (
+                StatusCode::FORBIDDEN,
+                StatusAction::Fail(DanbooruError::AuthFailed),
+            ),
+            (
+                StatusCode::TOO_MANY_REQUESTS,
+                StatusAction::Retry(DanbooruError::RateLimited),
+            ),
+            (
+                StatusCode::BAD_GATEWAY,
+                StatusAction::Retry(DanbooruError::Unavailable("502 Bad Gateway".into())),
+            ),
+        ];
+        for (status, expected) in cases {
+            assert_eq!(classify_status(status), expected, "{status}");
+        }
+    }
+
+    #[test]
+    fn body_classification() {
+        let posts = json!([{"id": 1}]);
+        assert_eq!(check_body(StatusCode::OK, posts.clone()), Ok(posts));
+        assert_eq!(
+            check_body(
+                StatusCode::OK,
+                json!({"success": false, "message": "bad search"})
+            ),
+            Err(DanbooruError::Unavailable("200 OK: bad search".into()))
+        );
+        assert_eq!(
+            check_body(StatusCode::UNPROCESSABLE_ENTITY, json!({})),
+            Err(DanbooruError::Unavailable(
+                "422 Unprocessable Entity: unknown error".into()
+            ))
+        );
+    }
+
+    #[test]
+    fn empty_results() {
+        assert_eq!(
+            empty_result_error("hakurei_reimu", true),
+            DanbooruError::NoResults("hakurei_reimu".into())
+        );
+        assert_eq!(
+            empty_result_error("hakurei_reimo", false),
+            DanbooruError::UnknownTag("hakurei_reimo".into())
+        );
+    }
+
+    #[test]
+    fn bucket_refills_over_time() {
+        let bucket = TokenBucket::new(2, 4.0);
+        let start = Instant::now();
+        let mut state = (2.0, start);
+        assert_eq!(bucket.take(&mut state, start), None);
+        assert_eq!(bucket.take(&mut state, start), None);
+        // Empty, a token takes a quarter of a second
+        assert_eq!(
+            bucket.take(&mut state, start),
+            Some(Duration::from_millis(250))
+        );
+        // Half a token in, half to go
+        let later = start + Duration::from_millis(125);
+        assert_eq!(
+            bucket.take(&mut state, later),
+            Some(Duration::from_millis(125))
+        );
+        assert_eq!(
+            bucket.take(&mut state, later + Duration::from_millis(125)),
+            None
+        );
+        // Never more than the capacity, however long it sat idle
+        let idle = later + Duration::from_secs(60);
+        assert_eq!(bucket.take(&mut state, idle), None);
+        assert_eq!(bucket.take(&mut state, idle), None);
+        assert!(bucket.take(&mut state, idle).is_some());
+    }
 }
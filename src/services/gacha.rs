@@ -1,25 +1,38 @@
-use crate::config::HTTP_CLIENT;
-use crate::models::prize::{Prize, PrizePhoto};
+use crate::composite::{self, CompositeConfig, Frame, Theme, Tile};
+use crate::config::FALLBACK_CHAIN;
+use crate::download;
+use crate::image_cache::IMAGE_CACHE;
+use crate::models::character::Character;
+use crate::models::prize::{Prize, PrizePhoto, PullSource};
 use crate::models::user::User;
+use crate::services::channel::get_channel_max_post_id;
+use crate::services::characters;
 use crate::services::danbooru::danbooru;
-use crate::store::STORE;
-use crate::utils::is_same_date_in_hkt;
+use crate::services::health::{Backend, CircuitOpen, HEALTH};
+use crate::store::{STORE, channel_prizes};
+use crate::utils::date_in_hkt;
 use anyhow::{Result, anyhow};
 use bytes::Bytes;
-use chrono::prelude::*;
-use futures::future::try_join_all;
+use chrono::Utc;
+use futures::future::{join_all, try_join_all};
+use grammers_client::Client;
+use grammers_client::media::PhotoSize;
 use grammers_client::message::{Button, InputMessage, ReplyMarkup};
-use image::{DynamicImage, ImageReader, RgbaImage, imageops::FilterType};
-use lol_html::{HtmlRewriter, Settings, element};
-use std::cell::RefCell;
-use std::io::Cursor;
-use std::rc::Rc;
+use image::DynamicImage;
+use rand::prelude::*;
+use rand::rng;
+use std::collections::HashSet;
+use std::fmt;
+use std::sync::Arc;
+use std::time::Instant;
 
 enum PrizeType {
     /// Prize from channel @WaifuP1c, Some(post_id), None -> random
     ChannelPrize(Option<i32>),
     /// Prize from Danbooru, with search tag and display name
     DanbooruPrize { tag: String, name: String },
+    /// Prize we have seen before, from `Store::prizes`
+    CachedPrize,
     /// Telegram user as prize, with uid
     #[allow(dead_code)]
     UserPrize(i64),
@@ -28,17 +41,89 @@ enum PrizeType {
     OtherPrize,
 }
 
+/// Prizes and the source in the fallback chain that served them
+pub struct Pull {
+    pub source: PullSource,
+    pub prizes: Vec<Prize>,
+}
+
+/// Every source in the fallback chain failed
+#[derive(Debug)]
+pub struct PullFailed {
+    pub errors: Vec<(PullSource, anyhow::Error)>,
+}
+
+impl PullFailed {
+    /// Why the user's special prize failed, if the chain got to it. The user asked for
+    /// that one, so it's the error worth showing them.
+    pub fn special_error(&self) -> Option<&anyhow::Error> {
+        self.errors
+            .iter()
+            .find(|(source, _)| *source == PullSource::Special)
+            .map(|(_, e)| e)
+    }
+}
+
+impl fmt::Display for PullFailed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "All pull sources failed:")?;
+        for (source, error) in &self.errors {
+            write!(f, " [{source:?}: {error:#}]")?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for PullFailed {}
+
 #[tracing::instrument(skip(user))]
-pub async fn pull(user: &User, n: usize) -> Result<Vec<Prize>> {
-    let prize_type = if let Some(special) = &user.special {
-        PrizeType::DanbooruPrize {
-            tag: special.search_tag.clone(),
-            name: special.display_name.clone(),
+pub async fn pull(user: &User, n: usize) -> Result<Pull> {
+    let mut errors = vec![];
+    for &source in FALLBACK_CHAIN.iter() {
+        let prize_type = match source {
+            PullSource::Special => match &user.special {
+                Some(special) => PrizeType::DanbooruPrize {
+                    tag: special.search_tag.clone(),
+                    name: special.display_name.clone(),
+                },
+                None => continue,
+            },
+            PullSource::Channel => PrizeType::ChannelPrize(None),
+            PullSource::LocalPool => PrizeType::CachedPrize,
+        };
+
+        // Don't make the user wait for a source we know is broken
+        let backend = match prize_type {
+            PrizeType::DanbooruPrize { .. } => Some(Backend::Danbooru),
+            PrizeType::ChannelPrize(_) => Some(Backend::ChannelMtproto),
+            _ => None,
+        };
+        if let Some(backend) = backend
+            && HEALTH.is_open(backend)
+        {
+            errors.push((source, CircuitOpen(backend).into()));
+            continue;
         }
-    } else {
-        PrizeType::ChannelPrize(None)
-    };
 
+        match pull_prize_type(prize_type, n).await {
+            Ok(prizes) if !prizes.is_empty() => {
+                tracing::info!(user = user.id, ?source, "Pull served");
+                return Ok(Pull { source, prizes });
+            }
+            Ok(_) => {
+                tracing::warn!(user = user.id, ?source, "Pull source returned nothing");
+                errors.push((source, anyhow!("no prizes")));
+            }
+            Err(e) => {
+                tracing::warn!(user = user.id, ?source, "Pull source failed: {:#}", e);
+                errors.push((source, e));
+            }
+        }
+    }
+    Err(PullFailed { errors }.into())
+}
+
+async fn pull_prize_type(prize_type: PrizeType, n: usize) -> Result<Vec<Prize>> {
     match prize_type {
         PrizeType::ChannelPrize(Some(post_id)) => {
             let mut store = STORE.get().await?;
@@ -54,13 +139,42 @@ pub async fn pull(user: &User, n: usize) -> Result<Vec<Prize>> {
             Ok(result)
         }
         PrizeType::ChannelPrize(None) => {
-            let max_post_id = get_channel_max_post_id().await?;
-            try_join_all((0..n).map(|_| async { pull_channel_prize(max_post_id).await })).await
+            let db = STORE.get().await?.database();
+            let post_ids = db.random_channel_posts(n as i64).await?;
+            if post_ids.is_empty() {
+                // The catalog is not built yet, probe random posts instead
+                let max_post_id = get_channel_max_post_id().await?;
+                return try_join_all(
+                    (0..n).map(|_| async { pull_channel_prize(max_post_id).await }),
+                )
+                .await;
+            }
+
+            let prizes = channel_prizes(&post_ids).await?;
+            let mut result = Vec::with_capacity(prizes.len());
+            for (post_id, prize) in post_ids.into_iter().zip(prizes) {
+                match prize {
+                    Some(prize) => result.push(prize),
+                    None => {
+                        tracing::warn!("Post {} is gone, retiring it from the catalog", post_id);
+                        db.set_channel_post_valid(post_id, false).await?;
+                    }
+                }
+            }
+            Ok(result)
         }
         PrizeType::DanbooruPrize { tag, name } => {
             let photos = danbooru(&tag, &name, n).await?;
             Ok(photos.into_iter().collect())
         }
+        PrizeType::CachedPrize => {
+            let store = STORE.get().await?;
+            let pool = store.prizes.values().collect::<Vec<_>>();
+            let mut rng = rng();
+            Ok((0..n)
+                .filter_map(|_| pool.choose(&mut rng).map(|&prize| prize.clone()))
+                .collect())
+        }
         PrizeType::UserPrize(_) => {
             todo!()
         }
@@ -71,99 +185,222 @@ pub async fn pull(user: &User, n: usize) -> Result<Vec<Prize>> {
     }
 }
 
+/// Nobody in the catalog goes by that name
+#[derive(Debug)]
+pub struct CharacterNotFound(pub String);
+
+impl fmt::Display for CharacterNotFound {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "No channel post for character '{}'", self.0)
+    }
+}
+
+impl std::error::Error for CharacterNotFound {}
+
+/// A random channel post featuring `character`
+#[tracing::instrument(skip(character), fields(character = character.id))]
+pub async fn pull_character(character: &Character) -> Result<Prize> {
+    let db = STORE.get().await?.database();
+    let aliases = characters::alias_pairs(&[character.id]);
+    let post_ids = db.random_channel_post_per_character(&aliases).await?;
+    let post_id = *post_ids
+        .get(&character.id)
+        .ok_or(CharacterNotFound(character.display_name().to_owned()))?;
+    let mut prizes = pull_prize_type(PrizeType::ChannelPrize(Some(post_id)), 1).await?;
+    prizes.pop().ok_or(anyhow!("Pull returned no prize"))
+}
+
+/// A random channel post for each character, None if it has none we can show.
+/// Unlike `pull_character`, the posts are picked in one query and fetched in one request.
+pub async fn character_prizes(characters: &[Arc<Character>]) -> Result<Vec<Option<(i32, Prize)>>> {
+    let db = STORE.get().await?.database();
+    let ids = characters.iter().map(|c| c.id).collect::<Vec<_>>();
+    let picked = db
+        .random_channel_post_per_character(&characters::alias_pairs(&ids))
+        .await?;
+    let post_ids = characters
+        .iter()
+        .map(|character| picked.get(&character.id).copied())
+        .collect::<Vec<_>>();
+
+    let wanted = post_ids.iter().flatten().copied().collect::<Vec<_>>();
+    let mut prizes = channel_prizes(&wanted).await?.into_iter();
+    Ok(post_ids
+        .into_iter()
+        .map(|post_id| {
+            let post_id = post_id?;
+            Some((post_id, prizes.next()??))
+        })
+        .collect())
+}
+
 #[tracing::instrument(skip(user))]
-pub async fn single_pull(user: &User) -> Result<Prize> {
-    let mut prize = pull(user, 1).await?;
-    Ok(prize.pop().unwrap())
+pub async fn single_pull(user: &User) -> Result<(PullSource, Prize)> {
+    let Pull { source, mut prizes } = pull(user, 1).await?;
+    let prize = prizes.pop().ok_or(anyhow!("Pull returned no prize"))?;
+    Ok((source, prize))
 }
 
 #[tracing::instrument(skip(user))]
 pub async fn ten_pulls(user: &User) -> Result<(InputMessage, PrizePhoto)> {
     tracing::debug!(user = user.id, "Running 10 pulls start");
-    let result = pull(user, 10).await?;
-    tracing::debug!(user = user.id, "Running 10 pulls end");
-    let client = {
-        let mut store = STORE.get().await?;
-        let _ = store.ten_pull_cache.insert(user.id, result.clone());
-        store.client.clone()
+    let Pull {
+        source,
+        prizes: mut result,
+    } = pull(user, 10).await?;
+    tracing::debug!(user = user.id, ?source, "Running 10 pulls end");
+    let (client, db) = {
+        let store = STORE.get().await?;
+        (store.client.clone(), store.database())
     };
-    let mut names = Vec::with_capacity(result.len());
-    let mut urls = Vec::with_capacity(result.len());
+    // Tile sizes from the photos' metadata decide which rendition to download,
+    // the composite is laid out again with the decoded images
+    let config = CompositeConfig::default();
+    let ratios = result
+        .iter()
+        .map(|prize| aspect_ratio(prize).unwrap_or(1.))
+        .collect::<Vec<_>>();
+    let targets = composite::tile_sizes(&ratios, &config)?;
+
     tracing::debug!(user = user.id, "Download start");
-    let imgs = try_join_all(result.into_iter().map(|prize| {
-        // ugly but convenient
-        names.push(prize.name);
-        urls.push(prize.url);
-        async {
-            let bytes = match prize.photo {
-                PrizePhoto::TelegramPhoto(photo) => {
-                    let mut download = client.iter_download(&photo);
-                    let mut bytes = vec![];
-                    while let Some(chunk) = download.next().await? {
-                        bytes.extend(chunk);
-                    }
-                    Ok(bytes.into())
+    let started = Instant::now();
+    // Each image is decoded as soon as its download completes
+    let loaded = join_all(
+        result
+            .iter()
+            .zip(&targets)
+            .enumerate()
+            .map(|(i, (prize, &target))| {
+                let client = &client;
+                async move {
+                    let image = load_tile(client, prize, target).await;
+                    tracing::debug!(tile = i + 1, ok = image.is_ok(), "Tile loaded");
+                    image
                 }
-                PrizePhoto::Url(url) => {
-                    let bytes = HTTP_CLIENT.get(url).send().await?.bytes().await?;
-                    Ok(bytes)
+            }),
+    )
+    .await;
+    // One bad image shouldn't ruin the other nine
+    let mut sources = vec![source; result.len()];
+    let mut imgs = Vec::with_capacity(loaded.len());
+    for (i, image) in loaded.into_iter().enumerate() {
+        let image = match image {
+            Ok(image) => image,
+            Err(e) => {
+                tracing::warn!(user = user.id, tile = i + 1, "Tile failed: {:#}", e);
+                match replacement_tile(&client, user, targets[i]).await {
+                    Ok((source, prize, image)) => {
+                        result[i] = prize;
+                        sources[i] = source;
+                        image
+                    }
+                    Err(e) => {
+                        tracing::warn!(user = user.id, tile = i + 1, "No replacement: {:#}", e);
+                        composite::placeholder(targets[i])
+                    }
                 }
-                _ => Err(anyhow!("bad")),
-            }?;
-            let img = ImageReader::new(Cursor::new(bytes))
-                .with_guessed_format()?
-                .decode()?;
-            let ok: Result<_> = Ok(img);
-            ok
-        }
-    }))
-    .await?;
-    tracing::debug!(user = user.id, "Download end");
+            }
+        };
+        imgs.push(image);
+    }
+    tracing::debug!(user = user.id, elapsed = ?started.elapsed(), "Download end");
 
-    tracing::debug!(user = user.id, "Composite end");
+    // Buttons pick from what is on the picture, replacements included
+    STORE
+        .get()
+        .await?
+        .ten_pull_cache
+        .insert(user.id, result.clone());
+    let names = result
+        .iter()
+        .map(|prize| prize.characters.clone())
+        .collect::<Vec<_>>();
+    let urls = result
+        .iter()
+        .map(|prize| prize.url.clone())
+        .collect::<Vec<_>>();
+
+    // Frame colours: the special prize, characters the user never pulled, the rest
+    let collected = db
+        .get_collection(user.id)
+        .await?
+        .into_iter()
+        .map(|(character_id, _)| character_id)
+        .collect::<HashSet<_>>();
+    let frames = result
+        .iter()
+        .zip(&sources)
+        .map(|(prize, &source)| {
+            let ids = characters::ids_of(&prize.characters);
+            if source == PullSource::Special {
+                Frame::Special
+            } else if !ids.is_empty() && ids.iter().all(|id| !collected.contains(id)) {
+                Frame::New
+            } else {
+                Frame::Common
+            }
+        })
+        .collect::<Vec<_>>();
+
+    tracing::debug!(user = user.id, "Composite start");
+    let started = Instant::now();
+    let tiles = imgs
+        .into_iter()
+        .zip(frames)
+        .map(|(image, frame)| Tile { image, frame })
+        .collect();
+    let composite =
+        composite::create_composite(tiles, config, Theme::seasonal(date_in_hkt(Utc::now())))
+            .await?;
     let photo = PrizePhoto::File {
-        name: "composite.jpg".into(),
-        content: create_composite(imgs)?,
+        name: composite.file_name,
+        content: composite.content,
+        as_document: composite.as_document,
     };
-    tracing::debug!(user = user.id, "Composite end");
+    tracing::debug!(user = user.id, elapsed = ?started.elapsed(), "Composite end");
 
     // We can just put prize info in the data though.
     // Currently we just don't.
     let mut button_data = [0u8; 15];
     button_data[0..6].copy_from_slice(b"option");
     button_data[6..14].copy_from_slice(&user.id.to_be_bytes());
-    let buttons = [1..=5, 6..=10].map(|range| {
-        range
-            .map(|i| {
-                button_data[14] = i;
-                Button::data(i.to_string(), button_data.to_vec())
-            })
-            .collect::<Vec<_>>()
-    });
+    // The catalog may return fewer than ten prizes, five buttons per row
+    let buttons = (1..=names.len() as u8)
+        .map(|i| {
+            button_data[14] = i;
+            Button::data(i.to_string(), button_data.to_vec())
+        })
+        .collect::<Vec<_>>()
+        .chunks(5)
+        .map(|row| row.to_vec())
+        .collect::<Vec<_>>();
     use grammers_tl_types::enums::MessageEntity;
     use grammers_tl_types::types::MessageEntityTextUrl;
     let mut buffer = String::with_capacity(512);
     let mut offset = 0i32;
-    let entities = names
-        .into_iter()
-        .zip(urls)
-        .enumerate()
-        .map(|(i, (name, url))| {
-            let prefix = format!("{}. ", i + 1);
-            offset += prefix.encode_utf16().count() as i32;
-            buffer.push_str(&prefix);
+    let mut entities = Vec::with_capacity(names.len());
+    for (i, (characters, url)) in names.into_iter().zip(urls).enumerate() {
+        let prefix = format!("{}. ", i + 1);
+        offset += prefix.encode_utf16().count() as i32;
+        buffer.push_str(&prefix);
+        // "1. A & B", each name linked
+        for (j, name) in characters.iter().enumerate() {
+            if j > 0 {
+                buffer.push_str(" & ");
+                offset += 3;
+            }
             let name_len = name.encode_utf16().count() as i32;
-            let entity = MessageEntity::TextUrl(MessageEntityTextUrl {
+            entities.push(MessageEntity::TextUrl(MessageEntityTextUrl {
                 offset,
                 length: name_len,
-                url,
-            });
-            buffer.push_str(&name);
-            buffer.push('\n');
-            offset += name_len + 1;
-            entity
-        })
-        .collect::<Vec<_>>();
+                url: url.clone(),
+            }));
+            buffer.push_str(name);
+            offset += name_len;
+        }
+        buffer.push('\n');
+        offset += 1;
+    }
     tracing::debug!(text = ?buffer, entities = ?entities);
     let input_message = InputMessage::new()
         .text(buffer)
@@ -172,55 +409,112 @@ pub async fn ten_pulls(user: &User) -> Result<(InputMessage, PrizePhoto)> {
     Ok((input_message, photo))
 }
 
-pub fn create_composite(images: Vec<DynamicImage>) -> Result<Bytes> {
-    let ratios = images
-        .iter()
-        .map(|img| img.width() as f64 / img.height() as f64)
-        .collect::<Vec<_>>();
-    let layout_config = crate::layout::LayoutConfig {
-        container_width: 1300.,
-        target_row_height: vec![440., 500.],
-        target_row_height_tolerance: 0.1,
-        edge_case_min_row_height_factor: 0.8,
-        edge_case_max_row_height_factor: 1.5,
-        ..Default::default()
-    };
-
-    let layout_result = crate::layout::compute(&ratios, &layout_config).unwrap();
-    let placements = layout_result.boxes;
+/// The tile image from the image cache, or downloaded in the smallest rendition that
+/// covers `target` and cached
+async fn load_tile(client: &Client, prize: &Prize, target: (u32, u32)) -> Result<DynamicImage> {
+    if let Some(key) = prize.image_key
+        && let Some(bytes) = IMAGE_CACHE.get(key).await
+    {
+        let image = composite::decode(bytes).await?;
+        // Cached for a smaller tile, fetch a larger rendition below
+        if image.width() + 1 >= target.0 && image.height() + 1 >= target.1 {
+            return Ok(image);
+        }
+    }
 
-    // 1. Determine the required canvas size
-    let max_w = layout_config.container_width;
-    let max_h = layout_result.container_height;
+    let started = Instant::now();
+    let bytes = download_photo(client, prize, target).await?;
+    tracing::debug!(size = bytes.len(), elapsed = ?started.elapsed(), "Downloaded");
+    let image = composite::decode(bytes).await?;
 
-    // 2. Initialize a transparent canvas
-    let mut canvas = RgbaImage::new(max_w.ceil() as u32, max_h.ceil() as u32);
+    if let Some(key) = prize.image_key {
+        let cached: Result<()> = async {
+            let bytes = composite::encode_for_cache(image.clone()).await?;
+            IMAGE_CACHE.put(key, &bytes).await
+        }
+        .await;
+        if let Err(e) = cached {
+            tracing::warn!("Failed to cache {:?}: {:#}", key, e);
+        }
+    }
+    Ok(image)
+}
 
-    // 3. Resize and overlay
-    for (img, p) in images.into_iter().zip(placements.into_iter()) {
-        let target_w = p.width.round() as u32;
-        let target_h = p.height.round() as u32;
+/// Another prize for a tile whose image failed, and the source that served it
+async fn replacement_tile(
+    client: &Client,
+    user: &User,
+    target: (u32, u32),
+) -> Result<(PullSource, Prize, DynamicImage)> {
+    let (source, prize) = single_pull(user).await?;
+    let image = load_tile(client, &prize, target).await?;
+    Ok((source, prize, image))
+}
 
-        // Resize the image to fit the placement
-        let resized = img.resize_exact(target_w, target_h, FilterType::Lanczos3);
+async fn download_photo(client: &Client, prize: &Prize, target: (u32, u32)) -> Result<Bytes> {
+    match &prize.photo {
+        PrizePhoto::TelegramPhoto(photo) => {
+            let sizes = photo.thumbs();
+            let size = composite::pick_size(
+                sizes
+                    .iter()
+                    .filter_map(|size| Some((size, photo_size_dimensions(size)?))),
+                target,
+            );
+            download::with_timeout("Telegram photo", async {
+                let mut download = match size {
+                    Some(size) => client.iter_download(size),
+                    None => client.iter_download(photo),
+                };
+                let mut bytes = vec![];
+                while let Some(chunk) = download.next().await? {
+                    download::ensure_within_limit(bytes.len(), chunk.len())?;
+                    bytes.extend(chunk);
+                }
+                download::sniff_image(&bytes)?;
+                Ok(bytes.into())
+            })
+            .await
+        }
+        PrizePhoto::Url(url) => {
+            let url = composite::pick_size(
+                prize
+                    .variants
+                    .iter()
+                    .map(|variant| (&variant.url, (variant.width, variant.height))),
+                target,
+            )
+            .unwrap_or(url);
+            download::fetch_image(url).await
+        }
+        PrizePhoto::File { .. } => Err(anyhow!("Local files can't be tiles")),
+    }
+}
 
-        // Overlay onto the canvas
-        // Note: replace() or overlay() are common. overlay() handles transparency.
-        image::imageops::overlay(
-            &mut canvas,
-            &resized,
-            p.left.round() as i64,
-            p.top.round() as i64,
-        );
+/// Width and height of a Telegram photo size, None for sizes we can't use as tiles
+fn photo_size_dimensions(size: &PhotoSize) -> Option<(u32, u32)> {
+    match size {
+        PhotoSize::Size(size) => Some((size.width as u32, size.height as u32)),
+        PhotoSize::Progressive(size) => Some((size.width as u32, size.height as u32)),
+        _ => None,
     }
+}
 
-    // Output as jpeg because telegram basically always converts our image to jpeg anyway.
-    // We also tried webp crate to create lossy webp, which only save us ~100kB. So whatever.
-    let mut buffer = vec![];
-    let final_img = DynamicImage::ImageRgba8(canvas);
-    let encoder = image::codecs::jpeg::JpegEncoder::new_with_quality(&mut buffer, 60);
-    final_img.write_with_encoder(encoder)?;
-    Ok(buffer.into())
+fn aspect_ratio(prize: &Prize) -> Option<f64> {
+    let (width, height) = match &prize.photo {
+        PrizePhoto::TelegramPhoto(photo) => photo
+            .thumbs()
+            .iter()
+            .filter_map(photo_size_dimensions)
+            .max_by_key(|&(w, h)| w as u64 * h as u64)?,
+        PrizePhoto::Url(_) => prize
+            .variants
+            .iter()
+            .map(|variant| (variant.width, variant.height))
+            .max_by_key(|&(w, h)| w as u64 * h as u64)?,
+        PrizePhoto::File { .. } => return None,
+    };
+    (height > 0).then(|| width as f64 / height as f64)
 }
 
 #[tracing::instrument(skip(max_post_id))]
@@ -235,7 +529,7 @@ async fn pull_channel_prize(max_post_id: i32) -> Result<Prize> {
             Err(e) => {
                 tracing::warn!("Failed to fetch post {}: {}", post_id, e);
                 // don't waste time if we just cannot read posts.
-                if e.is::<grammers_client::InvocationError>() {
+                if e.is::<grammers_client::InvocationError>() || e.is::<CircuitOpen>() {
                     return Err(e);
                 }
             }
@@ -243,54 +537,3 @@ async fn pull_channel_prize(max_post_id: i32) -> Result<Prize> {
     }
     Err(anyhow!("Could be unlucky like this??"))
 }
-
-#[tracing::instrument]
-async fn get_channel_max_post_id() -> Result<i32> {
-    let (max_post_id, cache_time) = {
-        let store = STORE.get().await?;
-        (
-            store.channel_max_post_id,
-            store.channel_max_post_id_cache_time,
-        )
-    };
-    let now = Utc::now();
-    if is_same_date_in_hkt(cache_time, now) {
-        return Ok(max_post_id);
-    }
-
-    // refresh
-    tracing::info!("Refeshing max post id...");
-    let new_max_post_id = {
-        let url = "https://t.me/s/WaifuP1c";
-        let html = HTTP_CLIENT.get(url).send().await?.text().await?;
-        let output = Rc::new(RefCell::new(String::new()));
-
-        let mut rewriter = HtmlRewriter::new(
-            Settings {
-                element_content_handlers: vec![element!(".tgme_widget_message", |el| {
-                    if let Some(attr) = el.get_attribute("data-post") {
-                        output.replace(attr);
-                    }
-
-                    Ok(())
-                })],
-                ..Settings::default()
-            },
-            |_: &[u8]| {},
-        );
-
-        rewriter.write(html.as_bytes())?;
-        rewriter.end()?;
-
-        let final_text = output.borrow().trim().to_string();
-        final_text[9..].parse::<i32>().ok()
-    };
-
-    let mut store = STORE.get().await?;
-    if let Some(new_max_post_id) = new_max_post_id {
-        tracing::info!("New max post id: {}", new_max_post_id);
-        store.update_channel_max_post_id(new_max_post_id);
-        return Ok(new_max_post_id);
-    }
-    Err(anyhow!("Cannot extract last post id"))
-}
@@ -0,0 +1,231 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{LazyLock, Mutex};
+use std::time::{Duration, Instant};
+
+/// Open the circuit after this many consecutive failures
+const FAILURE_THRESHOLD: u32 = 5;
+/// How long an open circuit rejects calls before going half-open and letting calls through
+const COOL_DOWN: Duration = Duration::from_secs(60);
+
+pub static HEALTH: LazyLock<Health> = LazyLock::new(Health::default);
+
+/// Upstreams a pull depends on
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Backend {
+    /// Reading @WaifuP1c posts through MTProto
+    ChannelMtproto,
+    /// Scraping t.me/WaifuP1c/<id>?embed=1 for captions
+    TmeEmbed,
+    /// Scraping t.me/s/WaifuP1c for the latest post id
+    TmeChannelPage,
+    Danbooru,
+}
+
+impl Backend {
+    const ALL: [Backend; 4] = [
+        Backend::ChannelMtproto,
+        Backend::TmeEmbed,
+        Backend::TmeChannelPage,
+        Backend::Danbooru,
+    ];
+}
+
+#[derive(Debug)]
+pub struct CircuitOpen(pub Backend);
+
+impl fmt::Display for CircuitOpen {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Circuit open for {:?}, skipping", self.0)
+    }
+}
+
+impl std::error::Error for CircuitOpen {}
+
+#[derive(Default)]
+struct BackendStats {
+    successes: u64,
+    failures: u64,
+    consecutive_failures: u32,
+    total_latency: Duration,
+    last_failure: Option<Instant>,
+    last_error: Option<String>,
+}
+
+impl BackendStats {
+    fn is_open(&self) -> bool {
+        self.consecutive_failures >= FAILURE_THRESHOLD
+            && self
+                .last_failure
+                .is_some_and(|time| time.elapsed() < COOL_DOWN)
+    }
+}
+
+#[derive(Default)]
+pub struct Health {
+    stats: Mutex<HashMap<Backend, BackendStats>>,
+}
+
+impl Health {
+    /// Whether calls to `backend` are currently being rejected.
+    /// Once the cool-down passes the circuit is half-open: calls go through,
+    /// one success closes it and one failure opens it again.
+    pub fn is_open(&self, backend: Backend) -> bool {
+        let stats = self.stats.lock().unwrap();
+        stats.get(&backend).is_some_and(BackendStats::is_open)
+    }
+
+    pub fn record<T, E: fmt::Display>(
+        &self,
+        backend: Backend,
+        latency: Duration,
+        result: &Result<T, E>,
+    ) {
+        let mut stats = self.stats.lock().unwrap();
+        let entry = stats.entry(backend).or_default();
+        entry.total_latency += latency;
+        match result {
+            Ok(_) => {
+                entry.successes += 1;
+                entry.consecutive_failures = 0;
+            }
+            Err(e) => {
+                entry.failures += 1;
+                entry.consecutive_failures += 1;
+                entry.last_failure = Some(Instant::now());
+                entry.last_error = Some(e.to_string());
+                if entry.consecutive_failures == FAILURE_THRESHOLD {
+                    tracing::warn!(?backend, "Circuit opened: {}", e);
+                }
+            }
+        }
+    }
+
+    /// Run `fut` against `backend`, failing fast if its circuit is open.
+    pub async fn track<T, E>(
+        &self,
+        backend: Backend,
+        fut: impl Future<Output = Result<T, E>>,
+    ) -> Result<T, E>
+    where
+        E: From<CircuitOpen> + fmt::Display,
+    {
+        if self.is_open(backend) {
+            return Err(CircuitOpen(backend).into());
+        }
+        let start = Instant::now();
+        let result = fut.await;
+        self.record(backend, start.elapsed(), &result);
+        result
+    }
+
+    /// Human readable summary for /status
+    pub fn report(&self) -> String {
+        let stats = self.stats.lock().unwrap();
+        let mut buffer = String::new();
+        for backend in Backend::ALL {
+            let Some(entry) = stats.get(&backend) else {
+                buffer.push_str(&format!("{backend:?}: no calls yet\n"));
+                continue;
+            };
+            let state = if entry.is_open() {
+                "OPEN"
+            } else if entry.consecutive_failures >= FAILURE_THRESHOLD {
+                "half-open"
+            } else {
+                "closed"
+            };
+            let calls = entry.successes + entry.failures;
+            let avg_latency = entry.total_latency / calls.max(1) as u32;
+            buffer.push_str(&format!(
+                "{backend:?}: {state}, ok {}, failed {} ({} in a row), avg {:?}\n",
+                entry.successes, entry.failures, entry.consecutive_failures, avg_latency,
+            ));
+            if let Some(error) = &entry.last_error {
+                buffer.push_str(&format!("  last error: {error}\n"));
+            }
+        }
+        buffer
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fail(health: &Health) {
+        health.record::<(), _>(Backend::Danbooru, Duration::ZERO, &Err("boom"));
+    }
+
+    fn succeed(health: &Health) {
+        health.record::<_, &str>(Backend::Danbooru, Duration::ZERO, &Ok(()));
+    }
+
+    /// Pretend the cool-down is over
+    fn cool_down(health: &Health) {
+        let mut stats = health.stats.lock().unwrap();
+        let entry = stats.get_mut(&Backend::Danbooru).unwrap();
+        entry.last_failure = entry
+            .last_failure
+            .and_then(|time| time.checked_sub(COOL_DOWN));
+    }
+
+    #[test]
+    fn opens_after_consecutive_failures() {
+        let health = Health::default();
+        for _ in 1..FAILURE_THRESHOLD {
+            fail(&health);
+        }
+        // A success in between starts the count over
+        succeed(&health);
+        for _ in 1..FAILURE_THRESHOLD {
+            fail(&health);
+        }
+        assert!(!health.is_open(Backend::Danbooru));
+        fail(&health);
+        assert!(health.is_open(Backend::Danbooru));
+        assert!(!health.is_open(Backend::TmeEmbed));
+        assert!(health.report().contains("Danbooru: OPEN"));
+    }
+
+    #[test]
+    fn half_open_failure_opens_again() {
+        let health = Health::default();
+        for _ in 0..FAILURE_THRESHOLD {
+            fail(&health);
+        }
+        cool_down(&health);
+        assert!(!health.is_open(Backend::Danbooru));
+        assert!(health.report().contains("Danbooru: half-open"));
+        fail(&health);
+        assert!(health.is_open(Backend::Danbooru));
+    }
+
+    #[test]
+    fn half_open_success_closes() {
+        let health = Health::default();
+        for _ in 0..FAILURE_THRESHOLD {
+            fail(&health);
+        }
+        cool_down(&health);
+        succeed(&health);
+        assert!(health.report().contains("Danbooru: closed"));
+        // Back to needing a full run of failures
+        fail(&health);
+        assert!(!health.is_open(Backend::Danbooru));
+    }
+
+    #[tokio::test]
+    async fn open_circuit_fails_fast() {
+        let health = Health::default();
+        for _ in 0..FAILURE_THRESHOLD {
+            fail(&health);
+        }
+        let result: anyhow::Result<()> = health
+            .track(Backend::Danbooru, async {
+                panic!("called an open backend")
+            })
+            .await;
+        assert!(result.unwrap_err().is::<CircuitOpen>());
+    }
+}
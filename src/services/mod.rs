@@ -1,2 +1,7 @@
+pub mod catalog;
+pub mod channel;
+pub mod characters;
 pub mod danbooru;
 pub mod gacha;
+pub mod health;
+pub mod search;
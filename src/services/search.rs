@@ -0,0 +1,197 @@
+// Fuzzy character search over every alias, for inline queries and /search.
+// Keys are folded to latin: Chinese characters become toneless pinyin and kana become romaji,
+// so "miku" finds #初音ミク and "lingmeng" finds #灵梦. Kanji have no reliable romaji without a
+// dictionary, so each character's kana reading (/alias_reading) is indexed too and "hatsune"
+// finds 初音ミク through はつねミク. Localized names (/alias_name) are aliases and indexed as such.
+use crate::models::character::Character;
+use pinyin::ToPinyin;
+use std::collections::HashMap;
+use std::sync::{Arc, LazyLock, RwLock};
+use wana_kana::ConvertJapanese;
+
+static INDEX: LazyLock<RwLock<Vec<Entry>>> = LazyLock::new(Default::default);
+
+struct Entry {
+    /// Normalized alias
+    alias: String,
+    /// `alias` folded to latin
+    folded: String,
+    character: Arc<Character>,
+}
+
+/// Lowercase, Chinese to pinyin, kana to romaji, without spaces and punctuation
+pub fn fold(s: &str) -> String {
+    let mut folded = String::with_capacity(s.len());
+    for ch in s.to_lowercase().chars() {
+        match ch.to_pinyin() {
+            Some(pinyin) => folded.push_str(pinyin.plain()),
+            None if ch.is_alphanumeric() => folded.push(ch),
+            None => {}
+        }
+    }
+    folded.to_romaji()
+}
+
+/// Called whenever the alias table is reloaded
+pub fn rebuild(aliases: &HashMap<String, Arc<Character>>) {
+    *INDEX.write().unwrap() = build(aliases);
+}
+
+fn build(aliases: &HashMap<String, Arc<Character>>) -> Vec<Entry> {
+    let mut index = aliases
+        .iter()
+        .map(|(alias, character)| Entry {
+            alias: alias.clone(),
+            folded: fold(alias),
+            character: Arc::clone(character),
+        })
+        .collect::<Vec<_>>();
+    // One reading per character, however many aliases it has
+    let mut seen = vec![];
+    for character in aliases.values() {
+        if seen.contains(&character.id) {
+            continue;
+        }
+        seen.push(character.id);
+        if let Some(reading) = &character.reading {
+            index.push(Entry {
+                alias: reading.trim().to_lowercase(),
+                folded: fold(reading),
+                character: Arc::clone(character),
+            });
+        }
+    }
+    index
+}
+
+/// Lower is better, None if it doesn't match at all
+fn score(entry: &Entry, query: &str, folded_query: &str) -> Option<usize> {
+    if entry.alias == query {
+        return Some(0);
+    }
+    if entry.alias.starts_with(query) {
+        return Some(1);
+    }
+    if entry.alias.contains(query) {
+        return Some(2);
+    }
+    if folded_query.is_empty() {
+        return None;
+    }
+    if entry.folded == folded_query {
+        return Some(3);
+    }
+    if entry.folded.starts_with(folded_query) {
+        return Some(4);
+    }
+    if entry.folded.contains(folded_query) {
+        return Some(5);
+    }
+    // One typo per four letters, short queries must be exact
+    let max_distance = (folded_query.chars().count() / 4).min(2);
+    if max_distance == 0 {
+        return None;
+    }
+    let distance = strsim::levenshtein(&entry.folded, folded_query);
+    (distance <= max_distance).then_some(6 + distance)
+}
+
+/// Best matching characters first, each character once
+pub fn search(query: &str, limit: usize) -> Vec<Arc<Character>> {
+    search_in(&INDEX.read().unwrap(), query, limit)
+}
+
+fn search_in(index: &[Entry], query: &str, limit: usize) -> Vec<Arc<Character>> {
+    let query = query.trim().trim_start_matches('#').to_lowercase();
+    if query.is_empty() {
+        return vec![];
+    }
+    let folded_query = fold(&query);
+
+    let mut matches = index
+        .iter()
+        .filter_map(|entry| Some((score(entry, &query, &folded_query)?, entry)))
+        .collect::<Vec<_>>();
+    // Shorter aliases are closer to what was typed
+    matches.sort_by_key(|(score, entry)| (*score, entry.alias.len()));
+
+    let mut result: Vec<Arc<Character>> = vec![];
+    for (_, entry) in matches {
+        if result.len() >= limit {
+            break;
+        }
+        if !result.iter().any(|c| c.id == entry.character.id) {
+            result.push(Arc::clone(&entry.character));
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn character(id: i64, canonical_name: &str, reading: Option<&str>) -> Arc<Character> {
+        Arc::new(Character {
+            id,
+            canonical_name: canonical_name.to_owned(),
+            name_zh: None,
+            name_ja: None,
+            name_en: None,
+            reading: reading.map(str::to_owned),
+        })
+    }
+
+    fn index(characters: &[(&str, &Arc<Character>)]) -> Vec<Entry> {
+        let aliases = characters
+            .iter()
+            .map(|(alias, character)| (alias.to_string(), Arc::clone(character)))
+            .collect();
+        build(&aliases)
+    }
+
+    fn found(index: &[Entry], query: &str) -> Vec<i64> {
+        search_in(index, query, 10).iter().map(|c| c.id).collect()
+    }
+
+    #[test]
+    fn fold_kana_and_hanzi() {
+        assert_eq!(fold("初音ミク"), "chuyinmiku");
+        assert_eq!(fold("はつね ミク"), "hatsunemiku");
+        assert_eq!(fold("灵梦"), "lingmeng");
+        assert_eq!(fold("Hatsune Miku!"), "hatsunemiku");
+    }
+
+    #[test]
+    fn romaji_finds_kanji_name_through_reading() {
+        let miku = character(1, "初音ミク", Some("はつねミク"));
+        let reimu = character(2, "博麗霊夢", Some("はくれいれいむ"));
+        let index = index(&[("初音ミク", &miku), ("miku", &miku), ("博麗霊夢", &reimu)]);
+
+        assert_eq!(found(&index, "hatsune"), [1]);
+        assert_eq!(found(&index, "hatsune miku"), [1]);
+        assert_eq!(found(&index, "hakurei"), [2]);
+        assert_eq!(found(&index, "reimu"), [2]);
+        // One typo per four letters
+        assert_eq!(found(&index, "hatsunemikku"), [1]);
+    }
+
+    #[test]
+    fn kanji_without_reading_needs_pinyin() {
+        let miku = character(1, "初音ミク", None);
+        let index = index(&[("初音ミク", &miku)]);
+
+        assert!(found(&index, "hatsune").is_empty());
+        assert_eq!(found(&index, "miku"), [1]);
+        assert_eq!(found(&index, "初音"), [1]);
+    }
+
+    #[test]
+    fn reading_indexed_once_per_character() {
+        let miku = character(1, "初音ミク", Some("はつねミク"));
+        let index = index(&[("初音ミク", &miku), ("miku", &miku), ("hatsunemiku", &miku)]);
+
+        assert_eq!(index.len(), 4);
+        assert_eq!(found(&index, "hatsu"), [1]);
+    }
+}
@@ -2,13 +2,20 @@ use crate::config::*;
 use anyhow::{Result, anyhow};
 use chrono::prelude::*;
 use grammers_client::Client;
-use grammers_session::types::PeerRef;
+use grammers_client::message::Message;
+use grammers_session::types::{PeerId, PeerRef};
 use std::collections::HashMap;
+use std::sync::OnceLock;
 use tokio::sync::{Mutex, OnceCell};
 
 use crate::db::Database;
-use crate::models::prize::{Prize, PrizePhoto, PrizeSource};
-use crate::models::user::{User, SpecialPrize};
+use crate::extractor::{self, Caption, Extracted};
+use crate::image_cache::ImageKey;
+use crate::models::prize::{Prize, PrizePhoto, PrizeSource, PullSource};
+use crate::models::user::{SpecialPrize, User, UserDTO};
+use crate::services::characters;
+use crate::services::health::{Backend, HEALTH};
+use crate::utils::is_same_date_in_hkt;
 
 #[derive(Clone)]
 pub struct Store {
@@ -17,10 +24,13 @@ pub struct Store {
     pub prizes: HashMap<i32, Prize>,
     /// key: user_id
     pub ten_pull_cache: HashMap<i64, Vec<Prize>>,
+    /// key: user_id, value: (as of, today's prize or None if not pulled yet).
+    /// Good until HKT midnight, see `today_prize`
+    today_prizes: HashMap<i64, (DateTime<Utc>, Option<Prize>)>,
     pub client: Client,
 
+    /// Highest post id seen in the channel, 0 if not loaded yet
     pub channel_max_post_id: i32,
-    pub channel_max_post_id_cache_time: DateTime<Utc>,
     pub waifu_pic_channel: PeerRef,
 }
 
@@ -30,9 +40,9 @@ impl Store {
             db: Database::new().await?,
             prizes: HashMap::new(),
             ten_pull_cache: HashMap::new(),
+            today_prizes: HashMap::new(),
             client,
             channel_max_post_id: 0,
-            channel_max_post_id_cache_time: DateTime::UNIX_EPOCH,
             waifu_pic_channel: waifu_pic_channel,
         })
     }
@@ -50,15 +60,7 @@ impl Store {
                     self.get_prize_from_channel_post(post_id).await?
                 }
                 PrizeSource::File { file_name: _ } => None,
-                PrizeSource::Url { photo_url } => {
-                    try {
-                        Prize {
-                            name: dto.waifu_name.clone()?,
-                            url: dto.waifu_url?,
-                            photo: PrizePhoto::Url(photo_url),
-                        }
-                    }
-                }
+                PrizeSource::Url { photo_url } => url_prize(&dto, photo_url),
             };
             Ok(Some(User {
                 id: dto.user_id,
@@ -90,55 +92,198 @@ impl Store {
         }
     }
 
-    pub async fn update_user_gacha(&self, user_id: i64, prize: Prize) -> Result<bool> {
-        self.db.update_gacha(user_id, prize).await
+    /// `source`: where the pull was served from, None keeps what we had
+    pub async fn update_user_gacha(
+        &mut self,
+        user_id: i64,
+        prize: Prize,
+        source: Option<PullSource>,
+    ) -> Result<bool> {
+        self.today_prizes
+            .insert(user_id, (Utc::now(), Some(prize.clone())));
+        self.db.update_gacha(user_id, prize, source).await
     }
 
     pub fn update_channel_max_post_id(&mut self, max_post_id: i32) {
-        self.channel_max_post_id = max_post_id;
-        self.channel_max_post_id_cache_time = Utc::now();
+        self.channel_max_post_id = self.channel_max_post_id.max(max_post_id);
+    }
+
+    pub fn database(&self) -> Database {
+        self.db.clone()
     }
 
     // it will update prize cache, so it is &mut self
     #[tracing::instrument(skip(self, post_id))]
     pub async fn get_prize_from_channel_post(&mut self, post_id: i32) -> Result<Option<Prize>> {
-        if let Some(cached) = self.prizes.get(&post_id) {
-            return Ok(Some(cached.clone()));
-        }
-        let channel = self.waifu_pic_channel;
-
-        if let Some(msg) = self
-            .client
-            .get_messages_by_id(channel, &[post_id])
-            .await?
-            .pop()
-            .flatten()
-        {
-            if let Some(photo) = msg.photo() {
-                let tmp: String;
-                let message: &str = if msg.text().is_empty() {
-                    tmp = crate::utils::parse_tg_embed_get_text(post_id).await?;
-                    &tmp
-                } else {
-                    msg.text()
-                };
-                if let Some(result) = CHARACTER_REGEXES
-                    .iter()
-                    .find_map(|reg| reg.captures(message))
+        let mut prizes = self.get_prizes_from_channel_posts(&[post_id]).await?;
+        Ok(prizes.pop().flatten())
+    }
+
+    /// Resolve several posts with at most one MTProto request.
+    /// The result is in the same order as `post_ids`.
+    #[tracing::instrument(skip(self))]
+    pub async fn get_prizes_from_channel_posts(
+        &mut self,
+        post_ids: &[i32],
+    ) -> Result<Vec<Option<Prize>>> {
+        let missing = post_ids
+            .iter()
+            .copied()
+            .filter(|post_id| !self.prizes.contains_key(post_id))
+            .collect::<Vec<_>>();
+
+        if !missing.is_empty() {
+            let messages =
+                fetch_channel_messages(&self.client, self.waifu_pic_channel, &missing).await?;
+            for (post_id, msg) in missing.into_iter().zip(messages) {
+                if let Some(msg) = msg
+                    && let Some(prize) = prize_from_channel_message(&msg).await?
                 {
-                    let url = format!("https://t.me/{CHANNEL_USERNAME}/{post_id}");
-                    let prize = Prize{
-                        name: result[1].to_owned(),
-                        url,
-                        photo: PrizePhoto::TelegramPhoto(photo)
-                    };
-                    self.prizes.insert(post_id, prize.clone());
-                    return Ok(Some(prize));
+                    self.prizes.insert(post_id, prize);
                 }
             }
         }
-        Ok(None)
+
+        Ok(post_ids
+            .iter()
+            .map(|post_id| self.prizes.get(post_id).cloned())
+            .collect())
+    }
+}
+
+/// Like `Store::get_prizes_from_channel_posts`, without holding the store while
+/// fetching, so other handlers aren't stuck behind a slow MTProto request
+pub async fn channel_prizes(post_ids: &[i32]) -> Result<Vec<Option<Prize>>> {
+    let (client, channel, missing) = {
+        let store = STORE.get().await?;
+        let missing = post_ids
+            .iter()
+            .copied()
+            .filter(|post_id| !store.prizes.contains_key(post_id))
+            .collect::<Vec<_>>();
+        (store.client.clone(), store.waifu_pic_channel, missing)
+    };
+
+    let mut fetched = Vec::with_capacity(missing.len());
+    if !missing.is_empty() {
+        let messages = fetch_channel_messages(&client, channel, &missing).await?;
+        for (post_id, msg) in missing.into_iter().zip(messages) {
+            if let Some(msg) = msg
+                && let Some(prize) = prize_from_channel_message(&msg).await?
+            {
+                fetched.push((post_id, prize));
+            }
+        }
+    }
+
+    let mut store = STORE.get().await?;
+    store.prizes.extend(fetched);
+    Ok(post_ids
+        .iter()
+        .map(|post_id| store.prizes.get(post_id).cloned())
+        .collect())
+}
+
+/// The prize of a Danbooru pull, rebuilt from what `update_gacha` saved
+fn url_prize(dto: &UserDTO, photo_url: String) -> Option<Prize> {
+    Some(Prize {
+        characters: vec![characters::display_name(dto.waifu_name.as_deref()?)],
+        series: None,
+        url: dto.waifu_url.clone()?,
+        photo: PrizePhoto::Url(photo_url),
+        variants: vec![],
+        image_key: None,
+    })
+}
+
+/// The user's prize if they pulled today, without creating the user.
+/// Inline queries ask on every keystroke, so the answer, "not yet" included, is cached per
+/// user until HKT midnight. The store is not held while reading the user and their post.
+pub async fn today_prize(user_id: i64) -> Result<Option<Prize>> {
+    let as_of = Utc::now();
+    let db = {
+        let store = STORE.get().await?;
+        if let Some((cached_at, prize)) = store.today_prizes.get(&user_id)
+            && is_same_date_in_hkt(*cached_at, as_of)
+        {
+            return Ok(prize.clone());
+        }
+        store.database()
+    };
+
+    let mut prize = None;
+    if let Some(dto) = db.get_user_by_id(user_id).await?
+        && is_same_date_in_hkt(dto.last_gacha_time.and_utc(), as_of)
+        && let Some(prize_json) = &dto.prize_json
+    {
+        prize = match serde_json::from_str(prize_json)? {
+            PrizeSource::Telegram { post_id } => channel_prizes(&[post_id]).await?.pop().flatten(),
+            PrizeSource::File { file_name: _ } => None,
+            PrizeSource::Url { photo_url } => url_prize(&dto, photo_url),
+        };
+    }
+
+    // A pull that landed meanwhile is newer than what we read
+    let mut store = STORE.get().await?;
+    if store
+        .today_prizes
+        .get(&user_id)
+        .is_none_or(|(cached_at, _)| *cached_at < as_of)
+    {
+        store.today_prizes.insert(user_id, (as_of, prize.clone()));
+    }
+    Ok(prize)
+}
+
+/// channels.getMessages accepts at most this many ids per call
+pub const MAX_MESSAGES_PER_REQUEST: usize = 100;
+
+pub async fn fetch_channel_messages(
+    client: &Client,
+    channel: PeerRef,
+    post_ids: &[i32],
+) -> Result<Vec<Option<Message>>> {
+    let mut result = Vec::with_capacity(post_ids.len());
+    for chunk in post_ids.chunks(MAX_MESSAGES_PER_REQUEST) {
+        let messages = HEALTH
+            .track(Backend::ChannelMtproto, async {
+                Ok::<_, anyhow::Error>(client.get_messages_by_id(channel, chunk).await?)
+            })
+            .await?;
+        result.extend(messages);
     }
+    Ok(result)
+}
+
+/// Turn a @WaifuP1c post into a prize, if it has a photo and a character tag
+pub async fn prize_from_channel_message(msg: &Message) -> Result<Option<Prize>> {
+    Ok(parse_channel_message(msg).await?.map(|(prize, _)| prize))
+}
+
+/// Like `prize_from_channel_message`, also returning what the extractor found
+pub async fn parse_channel_message(msg: &Message) -> Result<Option<(Prize, Extracted)>> {
+    let post_id = msg.id();
+    if let Some(photo) = msg.photo() {
+        let caption = if msg.text().is_empty() {
+            let embed = crate::tg_embed::fetch_caption(post_id).await?;
+            Caption::from_text_and_hashtags(embed.text, &embed.hashtags)
+        } else {
+            Caption::from_message(msg)
+        };
+        if let Some(extracted) = extractor::extract(&caption) {
+            let url = format!("https://t.me/{CHANNEL_USERNAME}/{post_id}");
+            let prize = Prize {
+                characters: characters::display_names(&extracted.characters),
+                series: extracted.series.clone(),
+                url,
+                photo: PrizePhoto::TelegramPhoto(photo),
+                variants: vec![],
+                image_key: Some(ImageKey::ChannelPost(post_id)),
+            };
+            return Ok(Some((prize, extracted)));
+        }
+    }
+    Ok(None)
 }
 
 pub struct StoreWrapper {
@@ -154,6 +299,7 @@ impl StoreWrapper {
 
     pub async fn init(&self, client: Client) -> Result<()> {
         let waifu_pic_channel = init_waifu_channel_info(&client).await?;
+        let _ = WAIFU_CHANNEL_ID.set(waifu_pic_channel.id);
         let inner = Store::new(client, waifu_pic_channel).await?;
         self.inner
             .set(Mutex::new(inner))
@@ -182,3 +328,6 @@ pub async fn init_waifu_channel_info(client: &Client) -> Result<PeerRef> {
 }
 
 pub static STORE: StoreWrapper = StoreWrapper::const_new();
+
+/// `Store::waifu_pic_channel`'s id, so updates can be told apart without locking the store
+pub static WAIFU_CHANNEL_ID: OnceLock<PeerId> = OnceLock::new();
@@ -0,0 +1,339 @@
+// Parser for t.me/<channel>/<post_id>?embed=1, used when MTProto gives us a post without text
+use crate::config::{CHANNEL_USERNAME, HTTP_CLIENT};
+use crate::services::health::{Backend, HEALTH};
+use anyhow::Result;
+use lol_html::{EndTagHandler, HtmlRewriter, Settings, element, text};
+use std::cell::RefCell;
+use std::rc::Rc;
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct EmbedCaption {
+    /// Caption as plain text, hashtags and link texts included
+    pub text: String,
+    /// Hashtags without the leading '#'
+    pub hashtags: Vec<String>,
+    pub links: Vec<EmbedLink>,
+    /// Post author signature, if the channel signs messages
+    pub author: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct EmbedLink {
+    pub text: String,
+    pub url: String,
+}
+
+#[derive(Default)]
+struct ParserState {
+    // Raw text, entities are decoded at the end
+    text: String,
+    // (start offset in text, href, end offset in text)
+    links: Vec<(usize, String, usize)>,
+    author: String,
+}
+
+pub async fn fetch_caption(post_id: i32) -> Result<EmbedCaption> {
+    let html = HEALTH
+        .track(Backend::TmeEmbed, async {
+            let url = format!("https://t.me/{CHANNEL_USERNAME}/{post_id}?embed=1");
+            Ok::<_, anyhow::Error>(HTTP_CLIENT.get(url).send().await?.text().await?)
+        })
+        .await?;
+    parse_caption(&html)
+}
+
+/// Handles the variants we have seen in the wild:
+/// - plain captions, with `<br>` line breaks
+/// - hashtags and mentions rendered as `<a>`
+/// - emoji rendered as `<i class="emoji">`, with or without the emoji in a `<b>`
+/// - several `.tgme_widget_message_text` blocks (albums), joined by a line break
+/// - `.tgme_widget_message_from_author` signatures
+pub fn parse_caption(html: &str) -> Result<EmbedCaption> {
+    let state = Rc::new(RefCell::new(ParserState::default()));
+
+    let mut rewriter = HtmlRewriter::new(
+        Settings {
+            element_content_handlers: vec![
+                element!(".tgme_widget_message_text", |_| {
+                    let mut state = state.borrow_mut();
+                    if !state.text.is_empty() && !state.text.ends_with('\n') {
+                        state.text.push('\n');
+                    }
+                    Ok(())
+                }),
+                text!(".tgme_widget_message_text", |t| {
+                    state.borrow_mut().text.push_str(t.as_str());
+                    Ok(())
+                }),
+                element!(".tgme_widget_message_text br", |_| {
+                    state.borrow_mut().text.push('\n');
+                    Ok(())
+                }),
+                element!(".tgme_widget_message_text a[href]", |el| {
+                    let href = el.get_attribute("href").unwrap_or_default();
+                    let start = state.borrow().text.len();
+                    let state = Rc::clone(&state);
+                    // Annotated, or the element handler's types get inferred from the closure
+                    let on_end: EndTagHandler<'static> = Box::new(move |_| {
+                        let mut state = state.borrow_mut();
+                        let end = state.text.len();
+                        state.links.push((start, href, end));
+                        Ok(())
+                    });
+                    if let Some(handlers) = el.end_tag_handlers() {
+                        handlers.push(on_end);
+                    }
+                    Ok(())
+                }),
+                element!(".tgme_widget_message_text i.emoji", |el| {
+                    // Newer embeds only have the emoji as a background image,
+                    // e.g. //telegram.org/img/emoji/40/F09F9296.png
+                    let emoji = el.get_attribute("style").and_then(|s| emoji_from_style(&s));
+                    let start = state.borrow().text.len();
+                    let state = Rc::clone(&state);
+                    if let Some(emoji) = emoji
+                        && let Some(handlers) = el.end_tag_handlers()
+                    {
+                        let on_end: EndTagHandler<'static> = Box::new(move |_| {
+                            let mut state = state.borrow_mut();
+                            if state.text.len() == start {
+                                state.text.push_str(&emoji);
+                            }
+                            Ok(())
+                        });
+                        handlers.push(on_end);
+                    }
+                    Ok(())
+                }),
+                text!(".tgme_widget_message_from_author", |t| {
+                    state.borrow_mut().author.push_str(t.as_str());
+                    Ok(())
+                }),
+            ],
+            ..Settings::default()
+        },
+        |_: &[u8]| {},
+    );
+
+    rewriter.write(html.as_bytes())?;
+    rewriter.end()?;
+
+    let state = state.borrow();
+    let mut caption = EmbedCaption {
+        text: decode_entities(state.text.trim()),
+        author: Some(decode_entities(state.author.trim())).filter(|author| !author.is_empty()),
+        ..Default::default()
+    };
+    for (start, href, end) in &state.links {
+        let text = decode_entities(state.text[*start..*end].trim());
+        if let Some(hashtag) = text.strip_prefix('#') {
+            caption.hashtags.push(hashtag.to_owned());
+        } else {
+            caption.links.push(EmbedLink {
+                text,
+                url: decode_entities(href),
+            });
+        }
+    }
+    Ok(caption)
+}
+
+fn emoji_from_style(style: &str) -> Option<String> {
+    let file_name = style.rsplit('/').next()?;
+    let hex = file_name.split('.').next()?;
+    // Checked before slicing, a non-ASCII file name would split a code point
+    if hex.is_empty() || hex.len() % 2 != 0 || !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return None;
+    }
+    let bytes = (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).ok())
+        .collect::<Option<Vec<_>>>()?;
+    String::from_utf8(bytes).ok()
+}
+
+/// lol_html hands us raw text, so `&amp;` and friends are still escaped
+fn decode_entities(s: &str) -> String {
+    let mut output = String::with_capacity(s.len());
+    let mut rest = s;
+    while let Some(amp) = rest.find('&') {
+        output.push_str(&rest[..amp]);
+        rest = &rest[amp..];
+        let decoded = rest.find(';').and_then(|semi| {
+            let entity = &rest[1..semi];
+            let ch = match entity {
+                "amp" => Some('&'),
+                "lt" => Some('<'),
+                "gt" => Some('>'),
+                "quot" => Some('"'),
+                "apos" => Some('\''),
+                "nbsp" => Some('\u{a0}'),
+                _ => {
+                    let code = if let Some(hex) = entity
+                        .strip_prefix("#x")
+                        .or_else(|| entity.strip_prefix("#X"))
+                    {
+                        u32::from_str_radix(hex, 16).ok()
+                    } else if let Some(dec) = entity.strip_prefix('#') {
+                        dec.parse().ok()
+                    } else {
+                        None
+                    };
+                    code.and_then(char::from_u32)
+                }
+            };
+            ch.map(|ch| (ch, semi))
+        });
+        match decoded {
+            Some((ch, semi)) => {
+                output.push(ch);
+                rest = &rest[semi + 1..];
+            }
+            None => {
+                output.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+    output.push_str(rest);
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fixture(name: &str) -> EmbedCaption {
+        let path = format!("{}/fixtures/tme/{name}", env!("CARGO_MANIFEST_DIR"));
+        let html = std::fs::read_to_string(&path).unwrap();
+        parse_caption(&html).unwrap()
+    }
+
+    #[test]
+    fn plain_caption_with_line_breaks() {
+        assert_eq!(
+            fixture("embed_plain.html"),
+            EmbedCaption {
+                text: "角色: 初音ミク\n作品: VOCALOID & more\n画师: 未知".into(),
+                ..Default::default()
+            }
+        );
+    }
+
+    #[test]
+    fn hashtags_and_links() {
+        assert_eq!(
+            fixture("embed_hashtags.html"),
+            EmbedCaption {
+                text: "角色: #初音ミク #HatsuneMiku\n作品: #VOCALOID\n来源: pixiv by @some_artist"
+                    .into(),
+                hashtags: vec!["初音ミク".into(), "HatsuneMiku".into(), "VOCALOID".into()],
+                links: vec![
+                    EmbedLink {
+                        text: "pixiv".into(),
+                        url: "https://www.pixiv.net/artworks/123?a=1&b=2".into(),
+                    },
+                    EmbedLink {
+                        text: "@some_artist".into(),
+                        url: "https://t.me/some_artist".into(),
+                    },
+                ],
+                author: None,
+            }
+        );
+    }
+
+    #[test]
+    fn emoji_from_background_image_or_text() {
+        let caption = fixture("embed_emoji.html");
+        // Style-only emoji are filled in, the one that has text isn't doubled
+        assert_eq!(caption.text, "今天也要元气满满💖\n角色: #芙兰蒂 ✨");
+        assert_eq!(caption.hashtags, vec!["芙兰蒂".to_owned()]);
+        assert!(caption.links.is_empty());
+    }
+
+    #[test]
+    fn emoji_with_non_ascii_file_name_is_skipped() {
+        let caption = fixture("embed_emoji_non_ascii.html");
+        assert_eq!(caption.text, "角色: #芙兰蒂");
+        assert_eq!(caption.hashtags, vec!["芙兰蒂".to_owned()]);
+    }
+
+    #[test]
+    fn album_text_blocks_are_joined() {
+        let caption = fixture("embed_album.html");
+        assert_eq!(caption.text, "角色: #雷姆 #拉姆\n作品: #Re0");
+        assert_eq!(
+            caption.hashtags,
+            vec!["雷姆".to_owned(), "拉姆".to_owned(), "Re0".to_owned()]
+        );
+        assert_eq!(caption.author, None);
+    }
+
+    #[test]
+    fn author_signature() {
+        let caption = fixture("embed_author.html");
+        assert_eq!(caption.text, "角色: #芙兰蒂");
+        assert_eq!(caption.hashtags, vec!["芙兰蒂".to_owned()]);
+        assert_eq!(caption.author.as_deref(), Some("Amber & Co"));
+    }
+
+    #[test]
+    fn page_without_caption() {
+        assert_eq!(parse_caption("").unwrap(), EmbedCaption::default());
+    }
+
+    #[test]
+    fn decode_named_and_numeric_entities() {
+        assert_eq!(
+            decode_entities("&amp;&lt;&gt;&quot;&apos;&nbsp;"),
+            "&<>\"'\u{a0}"
+        );
+        assert_eq!(decode_entities("it&#39;s &#x1F496;&#X2728;"), "it's 💖✨");
+        assert_eq!(decode_entities("plain text"), "plain text");
+    }
+
+    #[test]
+    fn decode_leaves_unknown_entities_alone() {
+        assert_eq!(decode_entities("AT&T"), "AT&T");
+        assert_eq!(decode_entities("&bogus; & &#xZZ;"), "&bogus; & &#xZZ;");
+        assert_eq!(decode_entities("&#1114112;"), "&#1114112;");
+        assert_eq!(decode_entities("trailing &"), "trailing &");
+    }
+
+    #[test]
+    fn emoji_from_background_image_style() {
+        assert_eq!(
+            emoji_from_style("background-image:url('//telegram.org/img/emoji/40/F09F9296.png')")
+                .as_deref(),
+            Some("💖")
+        );
+        assert_eq!(
+            emoji_from_style("background-image:url('//telegram.org/img/emoji/40/E29CA8.png')")
+                .as_deref(),
+            Some("✨")
+        );
+    }
+
+    #[test]
+    fn emoji_from_unusable_style() {
+        assert_eq!(emoji_from_style(""), None);
+        assert_eq!(
+            emoji_from_style("url('//telegram.org/img/emoji/40/F09F929.png')"),
+            None
+        );
+        assert_eq!(
+            emoji_from_style("url('//telegram.org/img/emoji/40/ZZZZ.png')"),
+            None
+        );
+        assert_eq!(
+            emoji_from_style("url('//telegram.org/img/emoji/40/表情.png')"),
+            None
+        );
+        // Not UTF-8
+        assert_eq!(
+            emoji_from_style("url('//telegram.org/img/emoji/40/FF.png')"),
+            None
+        );
+    }
+}